@@ -39,6 +39,12 @@ defmodule BashParser do
   Parse a Bash script string into an AST represented as a map.
 
   Returns `{:ok, ast_map}` on success or `{:error, reason}` on failure.
+
+  If the bundled grammar's ABI is not supported by the linked tree-sitter
+  runtime, every function that builds a parser (this one, `new_parser/0` and
+  its variants, `new_pool/2`, ...) returns
+  `{:error, %{"reason" => "language_load", "abi_version" => n, "expected" => m, "min_compatible" => k}}`,
+  where `expected` and `min_compatible` bound the ABI versions the runtime accepts.
   """
   def parse_bash(_content) do
     :erlang.nif_error(:nif_not_loaded)
@@ -65,7 +71,13 @@ defmodule BashParser do
   @doc """
   Parse incrementally by appending a fragment.
 
-  Returns `{:ok, ast}` or `{:error, reason}`.
+  Returns `{:ok, ast}` or `{:error, reason}`. Besides `changed_ranges` and
+  `changed_nodes`, the result has `reparsed_bytes` and `reused_bytes`: an
+  estimate of how much of the input tree-sitter had to reparse versus reuse.
+
+  `has_changes` is `false` when the edit left the syntax tree's structure
+  untouched (no changed ranges and the same top-level statement kinds), e.g.
+  whitespace added inside a string, so re-rendering can be skipped.
   """
   def parse_incremental(_resource, _fragment) do
     :erlang.nif_error(:nif_not_loaded)
@@ -80,6 +92,19 @@ defmodule BashParser do
     :erlang.nif_error(:nif_not_loaded)
   end
 
+  @doc """
+  Reparse the accumulated input from scratch, keeping the input.
+
+  Drops the incremental tree and stores the result of a clean full parse, as a
+  recovery path when the tree is suspected to have drifted from the input (for
+  example after a wrongly constructed edit). Returns `{:ok, ast}` in the
+  `parse_incremental/2` format, or `{:error, reason}` if the parse fails, in
+  which case the previous tree is kept.
+  """
+  def reparse_from_scratch(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
   @doc """
   Get the current AST without parsing.
 
@@ -110,9 +135,1097 @@ defmodule BashParser do
   @doc """
   Get accumulated input content.
 
-  Returns the accumulated string.
+  Returns the accumulated bytes exactly as appended, which may include
+  invalid UTF-8 fed through `parse_incremental_bytes/2`.
   """
   def get_accumulated_input(_resource) do
     :erlang.nif_error(:nif_not_loaded)
   end
+
+  @doc """
+  Run a tree-sitter S-expression query against the current tree.
+
+  Returns `{:ok, captures}` where each capture has `capture_name`, `node`,
+  and byte range, or `{:error, reason}` if the query fails to compile.
+
+  The standard text predicates `#eq?`, `#not-eq?`, `#match?`, `#not-match?`,
+  `#any-of?` and `#not-any-of?` are evaluated against capture text, and
+  matches that fail them are dropped. Any other `#name?` predicate is
+  rejected with a `:predicate` query error rather than silently ignored.
+
+  Pass `byte_range` as `{start_byte, end_byte}` to only match nodes that
+  intersect that region (e.g. the visible viewport). `match_limit` bounds how
+  many matches tree-sitter keeps in progress at once; on very large inputs
+  it trades completeness for latency, as matches beyond it are dropped.
+  """
+  def run_query(_resource, _query_string, _byte_range \\ nil, _match_limit \\ nil) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Find the smallest named node at a row/column position.
+
+  Returns `{:ok, node}` or `{:error, reason}` if the position is out of range.
+  """
+  def node_at_position(_resource, _row, _col) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Parse after replacing the byte range `start_byte..old_end_byte` with `new_text`.
+
+  Returns `{:ok, ast}` with change metadata, or `{:error, reason}` if the
+  range is out of bounds or the buffer would overflow.
+  """
+  def parse_edit(_resource, _start_byte, _old_end_byte, _new_text) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Dry-run `parse_edit/4`: parse the edit against a copy of the tree.
+
+  The resource is left untouched. Returns `{:ok, %{"has_errors" => bool,
+  "introduces_errors" => bool, "error_count" => n, "changed_ranges" => ranges}}`,
+  where `introduces_errors` is true when the edit would leave more error and
+  missing nodes than the current tree has. Errors are those of `parse_edit/4`.
+  """
+  def try_parse_edit(_resource, _start_byte, _old_end_byte, _new_text) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Replace the named node spanning exactly `start_byte..end_byte` with `new_text`.
+
+  Like `parse_edit/4`, but the range must belong to a real node, otherwise
+  `{:error, %{"reason" => "not_found"}}` is returned and nothing changes. On
+  success the result also carries `"node"`: the node that now spans the
+  replacement text (or `nil` if the text became part of a larger node), so
+  refactorings can be chained.
+  """
+  def replace_node(_resource, _start_byte, _end_byte, _new_text) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Rename a variable everywhere it appears, with a single reparse.
+
+  Every `variable_name` node whose text is `old_name` is rewritten: assignments
+  (`x=1`, `local x=1`), `$x`, `${x}` and `${x:-...}` expansions, arithmetic,
+  `for x in` and `unset x`. Text inside single-quoted strings and quoted
+  heredocs is never expanded by bash, so it is left alone. Plain words such as
+  the `x` in `read x` are not variable nodes and are not renamed either.
+
+  Pass `scope` as `{start_byte, end_byte}` to only rename occurrences inside that
+  range (e.g. a function body). Returns the `parse_edits/2` result plus
+  `"renamed"`, the number of occurrences changed, or
+  `{:error, %{"reason" => "invalid_name"}}` if `new_name` is not a valid shell name.
+  """
+  def rename_variable(_resource, _old_name, _new_name, _scope \\ nil) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Delete the byte range `start_byte..end_byte` and reparse incrementally.
+
+  Returns `{:ok, ast}` with change metadata, or `{:error, reason}` if the
+  range is out of bounds.
+  """
+  def delete_range(_resource, _start_byte, _end_byte) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Get the current AST converted at most `max_depth` levels deep.
+
+  Nodes at the cutoff include `truncated` and `child_count` in place of
+  their children. Returns `{:ok, ast}` or `{:error, reason}`.
+  """
+  def get_current_ast_shallow(_resource, _max_depth) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Fetch the subtree for the node spanning `start_byte..end_byte`.
+
+  Converts at most `max_depth` levels deep. If no node matches the range
+  exactly, the smallest containing node is returned with `range_adjusted`.
+  Returns `{:ok, node}` or `{:error, reason}`.
+  """
+  def get_subtree(_resource, _start_byte, _end_byte, _max_depth) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Collect every error and missing node in the current tree.
+
+  Returns `{:ok, errors}` where each entry has `type`, positions, and
+  `is_missing`, or `{:error, reason}` if nothing has been parsed.
+  """
+  def get_errors(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Find the earliest error or missing node in document order.
+
+  Returns `{:ok, error}` with the same keys as a `get_errors/1` entry, or
+  `{:ok, nil}` when the tree has no errors. Only the path down to the first
+  error is visited, so this is cheap enough to run on every keystroke.
+  """
+  def first_error_position(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Describe the context around each error node in the current tree.
+
+  Returns `{:ok, contexts}` where each entry has the error positions, the
+  surrounding source `snippet`, adjacent node kinds, and an `expected`/`hint`
+  pair such as "expected `fi` to close `if`", or `{:error, reason}`.
+  """
+  def get_error_context(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Create a new parser resource that keeps accumulated input within a sliding window.
+
+  Once the input exceeds `window_bytes`, `parse_incremental/2` evicts the oldest
+  complete top-level statements and reports the count as `evicted_bytes`.
+  Returns `{:ok, resource}` on success.
+  """
+  def new_parser_windowed(_max_buffer_size, _window_bytes) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Extract every command invocation in the current tree.
+
+  Returns `{:ok, commands}` where each entry has `name`, `args`, byte range,
+  and `dynamic_name` (true when the name is an expansion), or `{:error, reason}`.
+  """
+  def get_commands(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Extract every function definition in the current tree.
+
+  Returns `{:ok, functions}` where each entry has `name`, `style`
+  (`:posix` or `:function_keyword`), positions, the body byte range, and
+  the `commands` invoked in the body, or `{:error, reason}`.
+  """
+  def get_function_definitions(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Extract every variable assignment in the current tree.
+
+  Returns `{:ok, assignments}` where each entry has `name`, `value_text`,
+  `value_type` (`:word`, `:string`, `:command_substitution`, `:array`,
+  `:expansion`, `:empty`, or `:other`), `is_exported`, and byte range,
+  or `{:error, reason}`.
+  """
+  def get_variable_assignments(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Extract every variable reference (`$FOO`, `${FOO}`, `${FOO:-default}`).
+
+  Returns `{:ok, references}` where each entry has `name`, the full
+  expansion `text`, byte range, and `has_operator`, or `{:error, reason}`.
+  """
+  def get_variable_references(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Extract every command substitution, in both `$(...)` and backtick forms.
+
+  Returns `{:ok, substitutions}` where each entry has the inner
+  `command_text`, byte range, `style` (`:dollar_paren` or `:backtick`), and
+  nesting `depth`, or `{:error, reason}`.
+  """
+  def get_command_substitutions(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Extract every process substitution, `<(...)` and `>(...)`.
+
+  Returns `{:ok, substitutions}` where each entry has `direction` (`:input`
+  for `<(`, `:output` for `>(`), the inner `command_text`, `text`, `redirect`
+  (true when it is a redirection target, as in `> >(tee log)`), and the byte
+  range and positions, or `{:error, reason}`.
+  """
+  def get_process_substitutions(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Extract every heredoc in the current tree.
+
+  Returns `{:ok, heredocs}` where each entry has the `delimiter`,
+  `is_quoted` (literal body), `strip_tabs` (`<<-`), `is_terminated`,
+  the `body` text, and the body byte range, or `{:error, reason}`.
+  """
+  def get_heredocs(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Extract every file, heredoc, and herestring redirection.
+
+  Returns `{:ok, redirections}` where each entry has the `operator`
+  (including any descriptor, e.g. `"2>"`), the `target`, and the attached
+  `command` text and byte range, or `{:error, reason}`.
+  """
+  def get_redirections(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Extract every pipeline as an ordered list of its stages.
+
+  Returns `{:ok, pipelines}` where each entry has `commands` (each stage
+  with `name`, `args`, `type`, and `text`) and `uses_stderr_pipe` (true if
+  any stage is joined with `|&`), or `{:error, reason}`.
+  """
+  def get_pipelines(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Extract every comment in the current tree, in document order.
+
+  Returns `{:ok, comments}` where each entry has `text` and byte range,
+  or `{:error, reason}`.
+  """
+  def get_comments(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Get the interpreter named by the script's shebang line.
+
+  Returns `{:ok, {interpreter_path, args}}` when the first line is a `#!`
+  comment, e.g. `#!/usr/bin/env bash` gives `{:ok, {"/usr/bin/env", ["bash"]}}`,
+  and `{:ok, nil}` otherwise.
+  """
+  def get_shebang(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Get the current tree as a tree-sitter S-expression string.
+
+  Returns `{:ok, sexp}` or `{:error, reason}`.
+  """
+  def to_sexp(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Parse a Bash script string and return its tree-sitter S-expression.
+
+  Returns `{:ok, sexp}`. Unlike `parse_bash/1`, error nodes are kept in the output.
+  """
+  def to_sexp_string(_content) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Get the current AST serialized as a JSON string.
+
+  Field names match `get_current_ast/1`. Returns `{:ok, json}` or
+  `{:error, reason}`.
+  """
+  def get_current_ast_json(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Bound how long any single parse on this resource may take.
+
+  Parses that exceed the limit return `{:error, %{"reason" => "timeout"}}`.
+  A value of `0` disables the timeout. Returns `:ok`.
+  """
+  def set_timeout_micros(_resource, _micros) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Create a new parser resource whose parses can be cancelled with `cancel_parse/1`.
+
+  Returns `{:ok, resource}` on success.
+  """
+  def new_parser_cancellable do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Cancel the parse currently running on a cancellable resource.
+
+  The interrupted call returns `{:error, %{"reason" => "cancelled"}}` and leaves
+  the accumulated input and tree unchanged. A cancel issued while no parse is
+  running applies to the next one, even if that parse has not started yet; the
+  request is cleared once a parse finishes or is cancelled. Returns `:ok`, or
+  `:error` if the resource was not created with `new_parser_cancellable/0`.
+  """
+  def cancel_parse(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Create a pool of `size` parsers for concurrent stateless parsing.
+
+  Each `pool_parse/2` is bounded by `timeout_micros` like
+  `set_timeout_micros/2` (`0`, the default, disables the timeout).
+  Returns `{:ok, pool}` on success.
+  """
+  def new_pool(_size, _timeout_micros \\ 0) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Parse a Bash script with a parser checked out from the pool.
+
+  If every pooled parser is busy a temporary one is built and dropped
+  afterwards, so the pool never holds more than `size` parsers.
+  Returns `{:ok, ast}` (with `has_errors` when applicable), or
+  `{:error, %{"reason" => reason}}` with `"timeout"` or `"parse_error"`.
+  """
+  def pool_parse(_pool, _content) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Get the current AST with `start_col`/`end_col` in UTF-16 code units.
+
+  This matches the position encoding used by LSP clients. Returns
+  `{:ok, ast}` or `{:error, reason}`.
+  """
+  def get_current_ast_utf16(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Convert a byte offset in the accumulated input to a `{row, col}` point.
+
+  Returns `{:ok, {row, col}}` or `{:error, reason}` if the offset is out of range.
+  """
+  def byte_to_point(_resource, _byte_offset) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Convert a row/column point in the accumulated input to a byte offset.
+
+  Returns `{:ok, byte_offset}` or `{:error, reason}` if the point is out of range.
+  """
+  def point_to_byte(_resource, _row, _col) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Get shallow info for the smallest named node at a row/column position.
+
+  Returns `{:ok, info}` with `type`, positions, `named_child_count`, and
+  `child_count` (no converted children), or `{:error, reason}`.
+  """
+  def node_info_at(_resource, _row, _col) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Build a hierarchical outline of the script (LSP `documentSymbol` style).
+
+  Returns `{:ok, symbols}` where each symbol has `name`, `kind` (`:function`,
+  `:variable`, `:if`, `:case`, `:for`, `:while`, or `:until`), positions,
+  and nested `children`, or `{:error, reason}`.
+  """
+  def get_outline(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Produce syntax-highlighting spans for the current accumulated input.
+
+  Pass `:default` to use tree-sitter-bash's bundled highlights query, or a
+  query string of your own. Returns `{:ok, spans}` where each span is a map
+  with `"start_byte"`, `"end_byte"` and `"highlight_name"`, attributed to the
+  innermost capture, or `{:error, %{"reason" => ...}}`.
+  """
+  def highlight(_resource, _highlight_query) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Re-highlight only the region changed by the last parse.
+
+  Uses tree-sitter-bash's bundled highlights query. The changed byte ranges
+  (plus the edited text) are widened to whole top-level statements so that
+  multi-line tokens such as heredocs and strings are covered. Returns
+  `{:ok, %{"start_byte" => s, "end_byte" => e, "spans" => spans}}` where spans
+  use the same shape as `highlight/2` with absolute byte offsets.
+  """
+  def highlight_changed(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Export the parser's accumulated input and settings as a binary.
+
+  The tree-sitter tree is not serialized; `import_state/1` re-derives it by
+  parsing the saved input once. Input that is not valid UTF-8 is saved as a
+  byte array under `"input_bytes"` instead of the `"input"` string.
+  """
+  def export_state(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Rebuild a parser resource from a binary produced by `export_state/1`.
+
+  The input is re-parsed in one shot to recreate the tree. Returns
+  `{:ok, resource}` or `{:error, %{"reason" => reason}}` where reason is
+  `"invalid_state"`, `"buffer_overflow"` or a parse failure reason.
+  """
+  def import_state(_state) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Fork a parser resource, copying its accumulated input, tree and settings.
+
+  The clone has its own parser, so tentative edits applied to it leave the
+  original untouched.
+  """
+  def clone_parser(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Get complexity metrics for the current tree without serializing it.
+
+  Returns `{:ok, %{"node_count" => n, "named_node_count" => n, "max_depth" => d,
+  "error_count" => e, "byte_size" => b}}`, computed in a single cursor walk.
+  """
+  def get_tree_stats(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Count the named nodes of each kind in the current tree.
+
+  Returns `{:ok, %{"command" => 412, "expansion" => 98, ...}}`, computed in a
+  single cursor walk. Anonymous tokens such as keywords and punctuation are not
+  counted.
+  """
+  def kind_histogram(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Create a new parser whose AST maps leave out keys you don't need.
+
+  `opts` is a map with boolean keys (all default to `true`):
+
+    * `:include_error_flags` - emit `is_missing`, `is_extra`, `is_error`, `has_error`
+    * `:include_children` - emit field-less named children under `"children"`
+    * `:include_text` - emit each node's source `"text"`
+
+  `parse_incremental/2` and `get_current_ast/1` honor these options.
+
+  `:statement_container` takes a node kind (e.g. `"compound_statement"` for a
+  script wrapped in one function). Change detection then treats the named
+  children of the first node of that kind, rather than of `program`, as the
+  top-level statements. It falls back to `program` while no such node exists.
+  An unknown kind raises `ArgumentError`.
+
+  `include_timing: true` adds `parse_micros` (time spent in tree-sitter's parse)
+  and `convert_micros` (time building the result terms) to the results of
+  `parse_incremental/2` and the edit functions such as `parse_edit/4`. This
+  helps tell whether a latency spike comes from parsing or from conversion.
+  """
+  def new_parser_with_opts(_max_buffer_size, _opts) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Get the current AST, omitting the text of large nodes.
+
+  Any node whose span exceeds `max_text_bytes` gets `"text" => nil` and
+  `"text_truncated" => true`, plus `"start_byte"`/`"end_byte"` so the text can
+  be sliced from the accumulated input when needed.
+  """
+  def get_current_ast(_resource, _max_text_bytes) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Flag commands that match a risk rule, for "run this script?" prompts.
+
+  Built-in rules (`rule` atom / `severity`): `:rm_rf` (critical), `:mkfs`
+  (critical), `:device_write` (critical, redirects or `dd of=` to raw disks
+  such as `/dev/sd*`), `:curl_pipe_shell` (critical, `curl`/`wget` piped into
+  a shell), `:chmod_777` (high) and `:dd` (medium). Wrappers like `sudo` are
+  looked through.
+
+  `extra_rules` is a list of `{rule, command_name, arg_pattern}` tuples where
+  `arg_pattern` is a glob (`*`, `?`) matched against each argument, or `""`
+  to match any invocation. Custom matches are reported with severity `:high`.
+
+  Returns `{:ok, findings}` where each finding has the node range, `"rule"`,
+  `"severity"` and `"text"`.
+  """
+  def find_dangerous_commands(_resource, _extra_rules \\ []) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Find variable expansions used unquoted as command arguments.
+
+  Reports `$var` / `${var}` nodes that are a command argument (directly or
+  inside a concatenation such as `$dir/file`) or an operand of `[ ]`, where
+  word splitting and globbing apply. Expansions inside strings and `[[ ]]`
+  are not reported. Each entry has the node range, `"type"`, `"text"`,
+  `"variable"` and the `"command"` name.
+  """
+  def find_unquoted_expansions(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Classify the quoting of every command name and argument word.
+
+  Returns `{:ok, words}` in document order. Concatenations such as
+  `"$a"'b'c` are split into their parts. Each entry has:
+
+    * `"type"` - the node kind (`word`, `string`, `raw_string`, `ansi_c_string`, ...)
+    * `"quoting"` - `:unquoted`, `:single` (`'...'`), `:double` (`"..."` or
+      `$"..."`) or `:ansi_c` (`$'...'`)
+    * `"has_expansions"` - whether `$var`, `${...}`, `$(...)` or `$((...))` occur inside
+    * `"role"` - `:command_name` or `:argument`
+    * `"command"` - the command's name text
+    * `"text"` and the byte range and positions
+
+  Bare expansions such as `$x` are reported as `:unquoted` with
+  `has_expansions: true`.
+  """
+  def classify_words(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Describe the bash grammar: its node kinds and field names.
+
+  Returns `{:ok, %{"node_kind_count" => n, "node_kinds" => [...],
+  "field_names" => [...]}}` where `"node_kinds"` lists the named kinds.
+  """
+  def language_info do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Validate a tree-sitter query string without running it.
+
+  Returns `{:ok, %{"pattern_count" => n, "capture_names" => [...]}}` or
+  `{:error, %{"reason" => "query_error", "kind" => kind, "offset" => o, ...}}`
+  where `kind` is one of `:syntax`, `:node_type`, `:field`, `:capture`,
+  `:predicate`, `:structure` or `:language`.
+  """
+  def validate_query(_query_string) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Parse a fragment like `parse_incremental/2`, streaming results to `pid`.
+
+  Sends `{:changed_node, node_map}` for each changed node followed by
+  `{:parse_done, stats}`, and returns `{:ok, stats}`. Stats contain
+  `"changed_node_count"`, `"has_errors"`, `"byte_size"` and, for windowed
+  parsers, `"evicted_bytes"`. Auto-flush parsers first send
+  `{:flushed_node, node_map}` for each flushed statement and report
+  `"flushed_bytes"`. Errors are returned as `{:error, map}` and no messages
+  are sent.
+  """
+  def parse_incremental_streaming(_resource, _fragment, _pid) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Replace the whole document with `full_text` and reparse incrementally.
+
+  The previous and new text are compared to find the smallest changed span
+  (common prefix and suffix are trimmed), which is applied as a single edit so
+  `changed_ranges` stay accurate. Returns the same shape as `parse_edit/4`.
+  """
+  def set_document(_resource, _full_text) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Find constructs that are opened but never closed.
+
+  Covers `if`/`case`/loops/braces/subshells whose closer is missing, quotes
+  that run to the end of input, and heredocs without their end delimiter.
+  Returns `{:ok, list}` where each entry has `"construct"` (the node kind,
+  e.g. `"if_statement"`), `"open_range"` (position map of the opening token)
+  and `"expected_close"` (e.g. `"fi"`, or the heredoc delimiter).
+  """
+  def find_unterminated(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Parse only selected regions of `content` as bash.
+
+  `ranges` is a list of `{start_byte, end_byte, {start_row, start_col},
+  {end_row, end_col}}` tuples, sorted and non-overlapping, e.g. the fenced
+  code blocks of a Markdown file. Node positions in the result are relative to
+  the full `content`. The resource's accumulated input and tree are not
+  changed. Invalid ranges return `{:error, %{"reason" => "invalid_ranges",
+  "index" => i}}`.
+  """
+  def parse_ranges(_resource, _content, _ranges) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Get the ancestor chain of the node at a row/column position.
+
+  Returns `{:ok, list}` ordered innermost first (the smallest named node at
+  the position, then each parent up to `program`), where each entry is a map
+  with `"type"`, `"start_byte"` and `"end_byte"`.
+  """
+  def ancestors_at(_resource, _row, _col) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Get the path to the smallest named node at a row/column position.
+
+  Returns `{:ok, path}` where `path` lists child indices from the root down,
+  e.g. `[0, 2, 1]` (`[]` is the root itself). Indices count every child,
+  including anonymous tokens such as keywords and punctuation. Paths usually
+  survive reparses where byte offsets shift, so they suit structural
+  selections. See `node_at_path/2` for the inverse.
+  """
+  def node_path_at(_resource, _row, _col) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Get the node at a path returned by `node_path_at/3`.
+
+  Returns `{:ok, node_map}`, or `{:error, %{"reason" => "invalid_path", "index" => i}}`
+  when the `i`th index in the path does not name an existing child.
+  """
+  def node_at_path(_resource, _path) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Get the next named sibling of the node spanning exactly `start_byte..end_byte`.
+
+  Returns `{:ok, sibling}` with the sibling's `"type"` and position, `{:ok, nil}`
+  if there is none, or `{:error, %{"reason" => "not_found"}}` when no node
+  has that exact range.
+  """
+  def next_sibling(_resource, _start_byte, _end_byte) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Get the previous named sibling of the node spanning exactly `start_byte..end_byte`.
+
+  Returns `{:ok, sibling}` with the sibling's `"type"` and position, `{:ok, nil}`
+  if there is none, or `{:error, %{"reason" => "not_found"}}` when no node
+  has that exact range.
+  """
+  def prev_sibling(_resource, _start_byte, _end_byte) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Get the accumulated input between `start_byte` and `end_byte` as a binary.
+
+  Returns `{:ok, slice}`, or `{:error, %{"reason" => "out_of_range"}}` if the
+  range is outside the input or splits a UTF-8 character.
+  """
+  def get_input_range(_resource, _start_byte, _end_byte) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Get the number of lines in the accumulated input.
+
+  Counts newlines plus one, so empty input has one line and a trailing
+  newline starts a new (empty) line.
+  """
+  def get_line_count(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Get the byte offset where zero-based line `row` starts.
+
+  Returns `{:ok, byte_offset}` or `{:error, %{"reason" => "out_of_range"}}`.
+  """
+  def get_byte_offset_for_line(_resource, _row) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Check whether the accumulated input ends in a complete statement.
+
+  Returns `false` when the last top-level statement is unfinished: an open
+  quote or block (`if` without `fi`, ...), a trailing `\\` line continuation,
+  a dangling operator such as `|` or `&&`, or a heredoc without its end
+  delimiter. Use it to choose between a primary and continuation prompt.
+  """
+  def is_complete(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Extract every `case` statement with its subject and branches.
+
+  Each statement has its position, `"subject"` (the word being matched) and
+  `"branches"`. Each branch has its position, `"patterns"` (list of pattern
+  texts), `"body_start_byte"`/`"body_end_byte"` (`nil` for an empty body) and
+  `"termination"` (`";;"`, `";&"`, `";;&"` or `nil`).
+  """
+  def get_case_statements(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Extract `for`, `while` and `until` loops.
+
+  Every loop has its position, `"type"` (`:for`, `:while` or `:until`) and
+  `"body_start_byte"`/`"body_end_byte"`. `for ... in` loops add `"variable"`,
+  `"items"` (each with `"type"`, `"text"` and position, so a
+  `command_substitution` iterable is distinguishable from words) and
+  `"implicit_args"` (true when there is no `in` list). C-style loops add
+  `"c_style" => true` with `"initializer"`, `"condition"` and `"update"` text.
+  `while`/`until` loops add `"condition_start_byte"`/`"condition_end_byte"`.
+  """
+  def get_loops(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Re-emit the current script with consistent structure.
+
+  Bodies of `if`/`elif`/`else`, loops, functions, brace groups and `case`
+  branches are indented by two spaces, with `; then` / `; do` on the header
+  line. Simple statements, comments and heredocs are kept verbatim, and single
+  blank lines between statements are preserved. Comments between a header and
+  its `then`/`do`/`{` move to the top of the body; a loop whose header has a
+  comment in the middle is copied verbatim. Returns `{:ok, text}`, or
+  `{:error, %{"reason" => "has_errors"}}` when the tree contains syntax errors
+  and `{:error, %{"reason" => "invalid_utf8"}}` when the input is not valid UTF-8.
+  """
+  def format(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Get the current tree as a Graphviz DOT `digraph` string.
+
+  Each named node becomes a box labeled with its kind and a truncated text
+  snippet; edges point to named children and are labeled with the field name
+  where there is one.
+  """
+  def to_dot(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Create a new parser that keeps going when its buffer fills up.
+
+  When an append would exceed `max_buffer_size`, every complete top-level
+  statement before the trailing one is flushed: `parse_incremental/2` returns
+  them under `"flushed"` (with `"flushed_bytes"`) and the accumulated input is
+  compacted to the remaining region before the append is retried.
+  `buffer_overflow` is only returned if the input still does not fit.
+  """
+  def new_parser_auto_flush(_max_buffer_size) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Appends a raw binary fragment to the accumulated input and reparses incrementally.
+
+  Unlike `parse_incremental/2`, the fragment need not be valid UTF-8. The bytes
+  are stored and parsed exactly as sent, so byte offsets in every result match
+  the caller's bytes. Text fields covering invalid sequences are decoded
+  lossily, with U+FFFD in place of the bad bytes; such nodes carry
+  `"text_valid" => false` plus `"start_byte"`/`"end_byte"` so the original
+  bytes can be sliced from `get_accumulated_input/1`.
+  """
+  def parse_incremental_bytes(_resource, _fragment) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Sets the maximum buffer size of a parser resource at runtime, keeping its
+  incremental state.
+
+  Returns `{:ok, new_size}`, or `{:error, %{reason: "buffer_too_large"}}` when
+  the accumulated input is already longer than `new_size`. The input is never
+  truncated.
+  """
+  def set_max_buffer_size(_resource, _new_size) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Parses Bash content and always returns the AST, even when it contains syntax errors.
+
+  Returns `{:ok, ast}`. When tree-sitter had to recover from errors (for example a
+  missing `fi`), the root map also has `has_errors: true` and the offending nodes
+  carry `is_error` / `is_missing`. Use this instead of `parse_bash/1` to render
+  incomplete code.
+  """
+  def parse_bash_lenient(_content) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Returns every leaf token of the current tree in source order.
+
+  Includes unnamed tokens such as operators and keywords. Each token has `type`,
+  `named`, `text`, and the byte range and row/column positions. Zero-width tokens
+  that tree-sitter inserted during error recovery also carry `is_missing: true`.
+  """
+  def get_tokens(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Finds the structural partner of the delimiter at `byte_offset`.
+
+  Supports brackets and parentheses (including `$(`, `${`, `$((`, `[[` and
+  process substitutions), double, single and back quotes, `do`/`done`, `if`/`fi`
+  and `case`/`esac`. Returns `{:ok, %{type, start_byte, end_byte, ...}}` with the
+  partner's range, or `{:ok, nil}` when the offset is not on a delimiter or the
+  partner is missing.
+  """
+  def matching_delimiter(_resource, _byte_offset) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Classifies the lexical context at a cursor `byte_offset`.
+
+  Returns `{:ok, class}` where `class` is `:comment`, `:heredoc_body`,
+  `:variable` (a variable name or `$`/`${}` expansion), `:command_name`,
+  `:string` or `:code`. The innermost match wins, so `$x` inside a string is
+  `:variable` and a `$(cmd)` inside a string is `:command_name`. The character
+  before the cursor decides, so an offset just past a word describes the word
+  being typed.
+  """
+  def classify_offset(_resource, _byte_offset) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Returns `{:ok, true}` if any command in the current tree is invoked as `name`.
+
+  Cheaper than `get_commands/1` for allowlist checks: the walk stops as soon as
+  a match is found. Names are compared against the literal `command_name` text.
+  """
+  def contains_command(_resource, _name) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Finds `sudo`, `doas`, `su` and `pkexec` invocations in the current tree.
+
+  Each finding has `tool`, the target `user` (or `nil`), the escalated `command`
+  as a list of words (the `-c` string for `su`, empty for an interactive shell),
+  `text`, the byte range and positions, and `in_pipeline` / `in_substitution`
+  flags describing where the invocation appears.
+  """
+  def find_privilege_escalation(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Returns version information for the compiled-in Bash grammar.
+
+  The map has `abi_version` (the grammar's tree-sitter ABI), `grammar_version`
+  (the tree-sitter-bash version, e.g. `"0.25.0"`, or `nil` if unavailable), and
+  `min_abi_version` / `max_abi_version` accepted by the linked tree-sitter
+  runtime. Use it to check that custom query files match the grammar.
+  """
+  def language_version do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Parses input pulled chunk by chunk from `pid`, for files too large to pass as one binary.
+
+  Returns `{:ok, reader}` right away and parses on a separate thread. The thread
+  sends `{:chunk_request, reader, byte_offset}` to `pid`, which must answer with
+  `provide_chunk(reader, chunk)`, where `chunk` starts at `byte_offset`. An empty
+  binary marks the end of input and is only needed once. When the parse finishes, `pid` receives
+  `{:parse_done, reader, {:ok, ast}}` and the resource holds the new tree and
+  input, or `{:parse_done, reader, {:error, %{reason: reason}}}`.
+
+  The streamed bytes become the resource's accumulated input, because later
+  queries and edits read node text from it; only the caller is spared holding
+  the file as one binary. The total input is therefore still limited by the
+  resource's maximum buffer size. Chunks must be valid UTF-8 (a character may
+  be split across chunks), and the parse stops with `"invalid_utf8"` at the
+  first chunk that is not.
+
+  Each chunk must arrive within 30 seconds. The parse runs on its own parser,
+  so the resource stays usable meanwhile; on success the result replaces any
+  edits made to the resource in the meantime.
+  """
+  def parse_with_callback(_resource, _pid) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Answers a `{:chunk_request, reader, byte_offset}` message from `parse_with_callback/2`.
+
+  Send `<<>>` to signal the end of input.
+  """
+  def provide_chunk(_reader, _chunk) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Computes the single minimal edit that transforms `old_text` into `new_text`.
+
+  Returns `{:ok, {start_byte, old_end_byte, new_end_byte, positions}}`, where
+  `positions` has `start_position`, `old_end_position` and `new_end_position` as
+  `{row, col}` tuples with byte columns. Byte offsets always fall on character
+  boundaries, so the edit can be applied with
+  `parse_edit(resource, start_byte, old_end_byte, binary_part(new_text, start_byte, new_end_byte - start_byte))`.
+  """
+  def diff_documents(_old_text, _new_text) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Extracts test expressions from the current tree.
+
+  Covers POSIX `[ ... ]`, bash `[[ ... ]]` and the `test` builtin, told apart by
+  `style` (`:single_bracket`, `:double_bracket` or `:test_builtin`). Each entry has
+  the `expression` text without the brackets, the `operators` used in source
+  order (e.g. `["==", "-f"]`), `text`, and the byte range and positions.
+  """
+  def get_test_commands(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Returns every node of the given kind (e.g. `"concatenation"`) as AST maps, in document order.
+
+  Nested matches are included. Pass `max_results` to stop after that many nodes.
+  """
+  def find_nodes_of_kind(_resource, _kind, _max_results \\ nil) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Extracts arithmetic from the current tree.
+
+  Covers `$(( ... ))` and `$[ ... ]` expansions (`style: :expansion`) and
+  `(( ... ))` commands (`style: :command`). Each entry has the `expression` text
+  without delimiters, the distinct `variables` it references, `text`, and the
+  byte range and positions.
+  """
+  def get_arithmetic_expansions(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Returns the text of a byte range with surrounding source lines, for rendering diagnostic snippets.
+
+  The map has `text`, `start_row`, `end_row` and `lines`, a list of
+  `%{row, text, marker}` covering `context_lines` lines before and after the range.
+  `marker` is `{start_col, end_col}` (byte columns) on lines the range touches and
+  `nil` on pure context lines. Rows are zero-based.
+  """
+  def node_source_context(_resource, _start_byte, _end_byte, _context_lines) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Extracts alias definitions from `alias name=value` commands.
+
+  Each entry has the alias `name`, its `expansion` text with one level of quotes
+  removed, the byte range and positions of the defining argument, and `usages`:
+  the position maps of later commands invoked by that name, up to any
+  redefinition. `alias` calls without `=` (such as `alias -p`) are skipped.
+  """
+  def get_aliases(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Applies a batch of edits atomically and reparses once.
+
+  `edits` is a list of `{start_byte, old_end_byte, new_text}` tuples whose offsets
+  all refer to the current input. They may come in any order but must not
+  overlap; edits that only touch at an offset are fine. An insertion at the
+  start of a deleted range is placed before the replacement, and insertions
+  at the same offset keep the order given. Returns the same result as `parse_edit/4`, or `{:error, map}` with
+  `reason: "overlapping_edits"` and the conflicting `indices`,
+  `reason: "out_of_range"` and the offending `index`, or `reason: "buffer_overflow"`.
+  Nothing is changed on error.
+  """
+  def parse_edits(_resource, _edits) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Returns the `changed_ranges` of the most recent successful reparse again.
+
+  The ranges are stored on the resource, so a consumer other than the caller of
+  `parse_incremental/2` can read them later. Reading does not clear them. The
+  list is empty after a full parse or a reset.
+  """
+  def get_last_changed_ranges(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Runs a small set of shellcheck rules over the current tree.
+
+  Returns `{:ok, findings}`. Each finding has `code`, `message`, `severity`
+  (`:info` or `:warning`), `text`, and the byte range and positions. Supported codes:
+
+    * `SC2086` - unquoted `$var` / `${var}` subject to word splitting
+    * `SC2046` - unquoted `$(...)` subject to word splitting
+    * `SC2164` - `cd` whose failure is not handled (`|| exit`, `if cd ...`)
+    * `SC2115` - `rm -r` on `"$dir/..."` where an empty `$dir` would target `/`
+
+  Pass codes in `disabled` (e.g. `["SC2164"]`) to skip them.
+  """
+  def lint(_resource, _disabled \\ []) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Extracts subshells and command groups from the current tree.
+
+  `( ... )` subshells have `type: :subshell` and `{ ...; }` groups, which run in
+  the current shell, have `type: :group`. Each entry has `function_body` (true for
+  a function's `{ }` body), the `commands` it contains (in the `get_commands/1`
+  format, including those of nested blocks), `text`, and the byte range and
+  positions.
+  """
+  def get_subshells(_resource) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
 end
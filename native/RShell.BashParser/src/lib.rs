@@ -1,7 +1,13 @@
-use rustler::{Atom, Env, Error, NifResult, ResourceArc, Term};
-use std::collections::HashMap;
-use std::sync::Mutex;
-use tree_sitter::{InputEdit, Parser, Point, Range, Tree};
+use rustler::{Atom, Env, Error, LocalPid, NifResult, ResourceArc, Term};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+use tree_sitter_highlight::{HighlightConfiguration, HighlightEvent, Highlighter};
+use tree_sitter::{
+    InputEdit, ParseOptions, ParseState, Parser, Point, Query, QueryCursor, Range, StreamingIterator, Tree,
+};
 
 mod atoms {
     rustler::atoms! {
@@ -10,6 +16,89 @@ mod atoms {
         buffer_overflow,
         parse_error,
         no_tree,
+        query_error,
+        out_of_range,
+        posix,
+        function_keyword,
+        word,
+        string,
+        command_substitution,
+        array,
+        expansion,
+        empty,
+        other,
+        dollar_paren,
+        backtick,
+        timeout,
+        cancelled,
+        function,
+        variable,
+        if_block = "if",
+        case_block = "case",
+        for_loop = "for",
+        while_loop = "while",
+        until_loop = "until",
+        default,
+        include_error_flags,
+        include_children,
+        include_text,
+        rm_rf,
+        mkfs,
+        dd,
+        chmod_777,
+        device_write,
+        curl_pipe_shell,
+        single_bracket,
+        double_bracket,
+        test_builtin,
+        command,
+        subshell,
+        group,
+        critical,
+        high,
+        medium,
+        warning,
+        info,
+        syntax,
+        node_type,
+        field,
+        capture,
+        predicate,
+        structure,
+        language,
+        changed_node,
+        parse_done,
+        chunk_request,
+        statement_container,
+        include_timing,
+        argument,
+        unquoted,
+        single,
+        double,
+        ansi_c,
+        input,
+        output,
+        code,
+        comment,
+        heredoc_body,
+        command_name,
+        flushed_node,
+    }
+}
+
+/// Lock a mutex even if a previous holder panicked
+/// Resource state is only written after a successful parse, so the guarded data is still
+/// consistent; clearing the poison keeps one bad call from bricking a long-lived resource
+trait LockRecover<T> {
+    fn lock_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> LockRecover<T> for Mutex<T> {
+    fn lock_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(|poisoned| {
+            self.clear_poison();
+            PoisonError::into_inner(poisoned)
+        })
     }
 }
 
@@ -18,25 +107,258 @@ mod atoms {
 pub struct ParserResource {
     parser: Mutex<Parser>,
     old_tree: Mutex<Option<Tree>>,
-    accumulated_input: Mutex<String>,
-    max_buffer_size: usize,
+    accumulated_input: Mutex<InputBuffer>,
+    /// Adjustable at runtime through set_max_buffer_size
+    max_buffer_size: AtomicUsize,
+    /// When set, parse_incremental evicts the oldest complete statements beyond this size
+    window_bytes: Option<usize>,
+    /// Maximum parse duration in microseconds (0 disables the timeout)
+    timeout_micros: AtomicU64,
+    /// Set to non-zero by cancel_parse to halt the parse in progress
+    cancellation_flag: Option<Arc<AtomicUsize>>,
+    /// Byte span (in current input) touched by the last successful reparse
+    last_changed_bytes: Mutex<Option<(usize, usize)>>,
+    /// Merged changed_ranges of the last successful reparse, kept for get_last_changed_ranges
+    last_changed_ranges: Mutex<Vec<Range>>,
+    /// Controls which keys parse results and get_current_ast include
+    convert_options: ConvertOptions,
+    /// On buffer overflow, flush complete leading statements instead of failing
+    auto_flush: bool,
+    /// Node kind whose named children change detection treats as the statements (root when None)
+    statement_container: Option<&'static str>,
+    /// Add parse_micros/convert_micros to parse_incremental and edit results
+    include_timing: bool,
 }
 
 impl ParserResource {
-    fn new(max_buffer_size: usize) -> Result<Self, String> {
-        let mut parser = Parser::new();
-        let bash_language = tree_sitter_bash::LANGUAGE.into();
-        
-        parser.set_language(&bash_language)
-            .map_err(|_| "Failed to set Bash language")?;
+    fn new(max_buffer_size: usize) -> Result<Self, LanguageLoadError> {
+        let parser = new_bash_parser()?;
         
         Ok(ParserResource {
             parser: Mutex::new(parser),
             old_tree: Mutex::new(None),
-            accumulated_input: Mutex::new(String::new()),
-            max_buffer_size,
+            accumulated_input: Mutex::new(InputBuffer::default()),
+            max_buffer_size: AtomicUsize::new(max_buffer_size),
+            window_bytes: None,
+            timeout_micros: AtomicU64::new(0),
+            cancellation_flag: None,
+            last_changed_bytes: Mutex::new(None),
+            last_changed_ranges: Mutex::new(Vec::new()),
+            convert_options: ConvertOptions::default(),
+            auto_flush: false,
+            statement_container: None,
+            include_timing: false,
         })
     }
+
+    /// The timeout and cancellation flag every parse on this resource is bounded by
+    fn limits(&self) -> ParseLimits<'_> {
+        ParseLimits {
+            timeout_micros: self.timeout_micros.load(Ordering::Relaxed),
+            cancellation_flag: self.cancellation_flag.as_deref(),
+        }
+    }
+
+    /// Lock the parser, then the tree, then the input: the order every path takes them in
+    fn lock_for_edit(&self) -> EditLocks<'_> {
+        EditLocks {
+            parser: self.parser.lock_recover(),
+            tree_slot: self.old_tree.lock_recover(),
+            input: self.accumulated_input.lock_recover(),
+        }
+    }
+}
+
+/// Guards held across an edit, so whatever the edit is computed from can't change before it lands
+struct EditLocks<'a> {
+    parser: MutexGuard<'a, Parser>,
+    tree_slot: MutexGuard<'a, Option<Tree>>,
+    input: MutexGuard<'a, InputBuffer>,
+}
+
+/// Accumulated input together with the byte offset at which each line starts
+/// The raw bytes are stored as received (they need not be valid UTF-8) and are what tree-sitter
+/// parses; edits update the index incrementally, so byte/point conversions are a binary search
+/// instead of a newline scan; reads go through Deref to the bytes
+#[derive(Clone)]
+struct InputBuffer {
+    bytes: Vec<u8>,
+    /// Always starts with 0; entry n is the offset just past the nth newline
+    line_starts: Vec<usize>,
+}
+
+impl Default for InputBuffer {
+    fn default() -> Self {
+        InputBuffer::from(Vec::new())
+    }
+}
+
+impl From<Vec<u8>> for InputBuffer {
+    fn from(bytes: Vec<u8>) -> Self {
+        let line_starts = std::iter::once(0).chain(newline_ends(&bytes, 0)).collect();
+        InputBuffer { bytes, line_starts }
+    }
+}
+
+impl From<String> for InputBuffer {
+    fn from(text: String) -> Self {
+        InputBuffer::from(text.into_bytes())
+    }
+}
+
+impl std::ops::Deref for InputBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl InputBuffer {
+    fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    fn push_bytes(&mut self, fragment: &[u8]) {
+        let offset = self.bytes.len();
+        self.bytes.extend_from_slice(fragment);
+        self.line_starts.extend(newline_ends(fragment, offset));
+    }
+
+    fn replace_range(&mut self, range: std::ops::Range<usize>, replacement: &[u8]) {
+        // Lines starting inside the replaced text go, later ones shift by the size change
+        let first_removed = self.line_starts.partition_point(|&start| start <= range.start);
+        let first_kept = self.line_starts.partition_point(|&start| start <= range.end);
+        let shift = |start: usize| start - range.len() + replacement.len();
+        let shifted: Vec<usize> = self.line_starts[first_kept..].iter().map(|&start| shift(start)).collect();
+
+        self.line_starts.truncate(first_removed);
+        self.line_starts.extend(newline_ends(replacement, range.start));
+        self.line_starts.extend(shifted);
+        self.bytes.splice(range, replacement.iter().copied());
+    }
+
+    fn truncate(&mut self, new_len: usize) {
+        self.bytes.truncate(new_len);
+        let kept = self.line_starts.partition_point(|&start| start <= new_len);
+        self.line_starts.truncate(kept);
+    }
+
+    fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    fn is_char_boundary(&self, byte_offset: usize) -> bool {
+        is_char_boundary(&self.bytes, byte_offset)
+    }
+
+    /// Like the free byte_to_point, in O(log lines)
+    fn byte_to_point(&self, byte_offset: usize) -> Point {
+        let row = self.line_starts.partition_point(|&start| start <= byte_offset) - 1;
+        Point {
+            row,
+            column: byte_offset - self.line_starts[row],
+        }
+    }
+
+    /// Convert a row/column point to a byte offset
+    /// Returns None if the row or column lies outside the input
+    fn point_to_byte(&self, row: usize, column: usize) -> Option<usize> {
+        let (line_start, line_end) = self.line_bounds(row)?;
+        (line_start + column <= line_end).then_some(line_start + column)
+    }
+
+    /// Bytes of the given row without its trailing newline
+    fn line(&self, row: usize) -> Option<&[u8]> {
+        let (line_start, line_end) = self.line_bounds(row)?;
+        Some(&self.bytes[line_start..line_end])
+    }
+
+    /// Byte range of a row, excluding its newline
+    fn line_bounds(&self, row: usize) -> Option<(usize, usize)> {
+        let line_start = *self.line_starts.get(row)?;
+        let line_end = self.line_starts.get(row + 1).map_or(self.bytes.len(), |next| next - 1);
+        Some((line_start, line_end))
+    }
+}
+
+/// Offsets just past each newline in bytes, shifted by offset
+fn newline_ends(bytes: &[u8], offset: usize) -> impl Iterator<Item = usize> + '_ {
+    bytes
+        .iter()
+        .enumerate()
+        .filter(|&(_, &byte)| byte == b'\n')
+        .map(move |(index, _)| offset + index + 1)
+}
+
+/// Whether byte_offset may start or end a range: the ends of the input, or any offset not inside
+/// a valid UTF-8 sequence (so for valid text this matches str::is_char_boundary)
+fn is_char_boundary(bytes: &[u8], byte_offset: usize) -> bool {
+    match bytes.get(byte_offset) {
+        Some(0x80..=0xbf) => !in_utf8_sequence(bytes, byte_offset),
+        Some(_) => true,
+        None => byte_offset == bytes.len(),
+    }
+}
+
+/// Whether the continuation byte at byte_offset belongs to a valid multi-byte sequence
+fn in_utf8_sequence(bytes: &[u8], byte_offset: usize) -> bool {
+    // A sequence is at most 4 bytes long, so its lead byte is one of the 3 before
+    (byte_offset.saturating_sub(3)..byte_offset).any(|lead| {
+        let width = match bytes[lead] {
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf4 => 4,
+            _ => return false,
+        };
+        lead + width > byte_offset && bytes.get(lead..lead + width).is_some_and(|sequence| std::str::from_utf8(sequence).is_ok())
+    })
+}
+
+/// ParserPool holds idle parsers for stateless parsing from many processes
+/// The lock only guards checkout/return, never the parse itself
+pub struct ParserPool {
+    idle: Mutex<Vec<Parser>>,
+    /// Most parsers kept idle; extras built under contention are dropped on return
+    size: usize,
+    /// Maximum parse duration in microseconds (0 disables the timeout)
+    timeout_micros: u64,
+}
+
+/// ChunkReader delivers chunks from Elixir to a parse_with_callback thread
+/// provide_chunk sends on the channel; an empty chunk marks the end of input
+pub struct ChunkReader {
+    sender: Mutex<mpsc::Sender<Vec<u8>>>,
+}
+
+/// How long a parse_with_callback thread waits for each chunk before giving up
+const CHUNK_REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The grammar's ABI is outside the range the linked tree-sitter runtime accepts
+/// Encodes as %{"reason" => "language_load", "abi_version", "expected", "min_compatible"}
+struct LanguageLoadError {
+    abi_version: usize,
+}
+
+impl rustler::Encoder for LanguageLoadError {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let mut map = error_map(env, "language_load");
+        map.insert("abi_version".to_string(), self.abi_version.encode(env));
+        map.insert("expected".to_string(), tree_sitter::LANGUAGE_VERSION.encode(env));
+        map.insert("min_compatible".to_string(), tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION.encode(env));
+        map.encode(env)
+    }
+}
+
+/// Create a bash parser with the language already set
+fn new_bash_parser() -> Result<Parser, LanguageLoadError> {
+    let mut parser = Parser::new();
+    let bash_language: tree_sitter::Language = tree_sitter_bash::LANGUAGE.into();
+
+    parser.set_language(&bash_language)
+        .map_err(|_| LanguageLoadError { abi_version: bash_language.abi_version() })?;
+
+    Ok(parser)
 }
 
 /// Create a new parser resource with default buffer size (10MB)
@@ -59,456 +381,5452 @@ fn new_parser_with_size(
     }
 }
 
-/// Parse incrementally by appending a fragment to accumulated input
-/// Uses tree-sitter's incremental parsing with InputEdit tracking
+/// Create a new parser resource that keeps accumulated input within a sliding window
+/// Oldest complete top-level statements are evicted once input exceeds window_bytes
 #[rustler::nif]
-fn parse_incremental<'env>(
-    env: Env<'env>,
-    resource: ResourceArc<ParserResource>,
-    fragment: String,
-) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
-    use rustler::Encoder;
-    
-    // Get old input length and calculate row count for InputEdit
-    let (old_len, old_row_count) = {
-        let input = resource.accumulated_input.lock().unwrap();
-        let row_count = input.matches('\n').count();
-        (input.len(), row_count)
-    };
-    
-    // Check buffer size before appending
-    {
-        let input = resource.accumulated_input.lock().unwrap();
-        if input.len() + fragment.len() > resource.max_buffer_size {
-            return Ok((atoms::error(), {
-                let mut map = HashMap::new();
-                map.insert("reason".to_string(), "buffer_overflow".encode(env));
-                map.insert("current_size".to_string(), input.len().encode(env));
-                map.insert("fragment_size".to_string(), fragment.len().encode(env));
-                map.insert("max_size".to_string(), resource.max_buffer_size.encode(env));
-                map
-            }));
-        }
+fn new_parser_windowed(
+    max_buffer_size: usize,
+    window_bytes: usize,
+) -> NifResult<(Atom, ResourceArc<ParserResource>)> {
+    match ParserResource::new(max_buffer_size) {
+        Ok(resource) => Ok((atoms::ok(), ResourceArc::new(ParserResource {
+            window_bytes: Some(window_bytes),
+            ..resource
+        }))),
+        Err(msg) => Err(Error::Term(Box::new(msg))),
     }
-    
-    // Append fragment to accumulated input
-    let new_len = {
-        let mut input = resource.accumulated_input.lock().unwrap();
-        input.push_str(&fragment);
-        input.len()
-    };
-    
-    // Calculate new row count after append
-    let new_row_count = {
-        let input = resource.accumulated_input.lock().unwrap();
-        input.matches('\n').count()
-    };
-    
-    // Create InputEdit for tree-sitter's incremental parsing
-    let input_edit = InputEdit {
-        start_byte: old_len,
-        old_end_byte: old_len,
-        new_end_byte: new_len,
-        start_position: Point {
-            row: old_row_count,
-            column: 0,
-        },
-        old_end_position: Point {
-            row: old_row_count,
-            column: 0,
-        },
-        new_end_position: Point {
-            row: new_row_count,
-            column: 0,
-        },
+}
+
+/// Create a new parser resource whose parses can be cancelled with cancel_parse
+#[rustler::nif]
+fn new_parser_cancellable() -> NifResult<(Atom, ResourceArc<ParserResource>)> {
+    match ParserResource::new(10 * 1024 * 1024) {
+        Ok(resource) => Ok((atoms::ok(), ResourceArc::new(ParserResource {
+            cancellation_flag: Some(Arc::new(AtomicUsize::new(0))),
+            ..resource
+        }))),
+        Err(msg) => Err(Error::Term(Box::new(msg))),
+    }
+}
+
+/// Create a new parser resource whose AST maps omit the keys disabled in opts
+/// opts is a map with boolean :include_error_flags, :include_children and :include_text (all default true)
+/// and an optional :statement_container node kind for change detection and :include_timing flag
+#[rustler::nif]
+fn new_parser_with_opts(
+    max_buffer_size: usize,
+    opts: Term,
+) -> NifResult<(Atom, ResourceArc<ParserResource>)> {
+    let convert_options = convert_options_from_term(opts)?;
+    let statement_container = match opts.map_get(atoms::statement_container()) {
+        Ok(kind) => Some(bash_node_kind(&kind.decode::<String>()?).ok_or(Error::BadArg)?),
+        Err(_) => None,
     };
-    
-    // Get old tree and apply edit (updates tree metadata for incremental parsing)
-    let old_tree_option = {
-        let mut tree_lock = resource.old_tree.lock().unwrap();
-        if let Some(ref mut old_tree) = *tree_lock {
-            // Apply edit to old tree's metadata - required for incremental parsing
-            old_tree.edit(&input_edit);
-        }
-        tree_lock.clone()
+    let include_timing = match opts.map_get(atoms::include_timing()) {
+        Ok(flag) => flag.decode()?,
+        Err(_) => false,
     };
-    
-    // Parse with old_tree as reference (tree-sitter reuses unchanged subtrees internally)
-    let input = resource.accumulated_input.lock().unwrap().clone();
-    let mut parser = resource.parser.lock().unwrap();
-    
-    match parser.parse(&input, old_tree_option.as_ref()) {
-        Some(new_tree) => {
-            let has_error = new_tree.root_node().has_error();
-            let ast = convert_node_to_map(&new_tree.root_node(), &input, env);
-            
-            // Extract changed ranges and nodes if we have an old tree
-            let (changed_ranges, changed_nodes) = if let Some(ref old_tree) = old_tree_option {
-                let ranges = extract_changed_ranges(&new_tree, old_tree, env);
-                let nodes = extract_changed_nodes(&new_tree, old_tree, &input, env);
-                (ranges, nodes)
-            } else {
-                // First parse - everything is new
-                // Extract top-level child nodes from the tree directly
-                let root = new_tree.root_node();
-                let mut children_nodes = Vec::new();
-                
-                let mut cursor = root.walk();
-                if cursor.goto_first_child() {
-                    loop {
-                        let child = cursor.node();
-                        if child.is_named() {
-                            let child_map = convert_node_to_map(&child, &input, env);
-                            children_nodes.push(child_map);
+
+    match ParserResource::new(max_buffer_size) {
+        Ok(resource) => Ok((atoms::ok(), ResourceArc::new(ParserResource {
+            convert_options,
+            statement_container,
+            include_timing,
+            ..resource
+        }))),
+        Err(msg) => Err(Error::Term(Box::new(msg))),
+    }
+}
+
+/// Create a new parser resource that flushes complete statements when the buffer fills
+/// parse_incremental then returns the dropped statements under "flushed" instead of buffer_overflow
+#[rustler::nif]
+fn new_parser_auto_flush(max_buffer_size: usize) -> NifResult<(Atom, ResourceArc<ParserResource>)> {
+    match ParserResource::new(max_buffer_size) {
+        Ok(resource) => Ok((atoms::ok(), ResourceArc::new(ParserResource {
+            auto_flush: true,
+            ..resource
+        }))),
+        Err(msg) => Err(Error::Term(Box::new(msg))),
+    }
+}
+
+/// Parse input pulled chunk by chunk from an Elixir process, for files too large for one binary
+/// Sends {:chunk_request, reader, byte_offset} to pid, which answers with provide_chunk/2 (<<>> at EOF);
+/// the parse runs on its own thread and finishes with {:parse_done, reader, {:ok, ast} | {:error, map}}
+/// The streamed bytes replace accumulated_input on success, since every later query and edit on the
+/// tree reads node text from it; the caller never has to hold the whole file as one binary
+#[rustler::nif]
+fn parse_with_callback(
+    resource: ResourceArc<ParserResource>,
+    pid: LocalPid,
+) -> NifResult<(Atom, ResourceArc<ChunkReader>)> {
+    let (sender, receiver) = mpsc::channel();
+    let reader = ResourceArc::new(ChunkReader { sender: Mutex::new(sender) });
+    let thread_reader = reader.clone();
+
+    // Chunk requests are sent from a plain OS thread, where OwnedEnv is allowed
+    std::thread::spawn(move || {
+        use rustler::Encoder;
+
+        let max_buffer_size = resource.max_buffer_size.load(Ordering::Relaxed);
+        let mut buffer: Vec<u8> = Vec::new();
+        // Bytes before valid_up_to are checked UTF-8; the rest is at most one incomplete sequence
+        let mut valid_up_to = 0;
+        let mut failure: Option<&'static str> = None;
+        let mut eof = false;
+        let mut msg_env = rustler::OwnedEnv::new();
+
+        // A private parser, so the resource stays usable while chunks are awaited
+        let parsed = new_bash_parser().map_err(|_| "failed_to_set_language").and_then(|mut parser| {
+            let mut read = |byte_offset: usize, _: Point| -> Vec<u8> {
+                // tree-sitter may revisit earlier offsets (and read past the end more than once),
+                // so every chunk is kept and reads after EOF are answered locally
+                while failure.is_none() && !eof && byte_offset >= buffer.len() {
+                    let requested = buffer.len();
+                    let _ = msg_env.send_and_clear(&pid, |env| {
+                        (atoms::chunk_request(), thread_reader.clone(), requested).encode(env)
+                    });
+                    match receiver.recv_timeout(CHUNK_REPLY_TIMEOUT) {
+                        Ok(chunk) if chunk.is_empty() => {
+                            eof = true;
+                            if valid_up_to < buffer.len() {
+                                failure = Some("invalid_utf8");
+                            }
                         }
-                        
-                        if !cursor.goto_next_sibling() {
-                            break;
+                        Ok(chunk) if buffer.len() + chunk.len() > max_buffer_size => failure = Some("buffer_overflow"),
+                        Ok(chunk) => {
+                            buffer.extend_from_slice(&chunk);
+                            // A sequence split across chunks is rechecked once the next chunk arrives
+                            match std::str::from_utf8(&buffer[valid_up_to..]) {
+                                Ok(_) => valid_up_to = buffer.len(),
+                                Err(error) if error.error_len().is_none() => valid_up_to += error.valid_up_to(),
+                                Err(_) => failure = Some("invalid_utf8"),
+                            }
                         }
+                        Err(_) => failure = Some("chunk_timeout"),
                     }
                 }
-                
-                (vec![], children_nodes)
+                // Hand back a bounded slice so long inputs aren't copied once per read
+                let end = buffer.len().min(byte_offset + 64 * 1024);
+                match failure {
+                    Some(_) => Vec::new(),
+                    None => buffer.get(byte_offset..end).unwrap_or_default().to_vec(),
+                }
             };
-            
-            // Store the new tree
-            {
-                let mut tree_lock = resource.old_tree.lock().unwrap();
-                *tree_lock = Some(new_tree);
-            }
-            
-            // Build result with AST and change metadata
-            let mut result = ast.clone();
-            if has_error {
-                result.insert("has_errors".to_string(), true.encode(env));
-            }
-            
-            result.insert("changed_ranges".to_string(), changed_ranges.encode(env));
-            result.insert("changed_nodes".to_string(), changed_nodes.encode(env));
-            
-            Ok((atoms::ok(), result))
-        }
-        None => {
-            Ok((atoms::error(), {
-                let mut map = HashMap::new();
-                map.insert("reason".to_string(), "parse_error".encode(env));
-                map
-            }))
-        }
-    }
+            parse_chunks_with_limits(resource.limits(), &mut parser, &mut read, None)
+        });
+
+        // A chunk failure ends the input early, so whatever tree came back is incomplete
+        let outcome = failure.map_or(parsed, Err);
+
+        let _ = msg_env.send_and_clear(&pid, |env| {
+            let result = match outcome {
+                Ok(tree) => {
+                    let mut ast = convert_node_to_map_with(&tree.root_node(), &buffer, env, &resource.convert_options);
+                    if tree.root_node().has_error() {
+                        ast.insert("has_errors".to_string(), true.encode(env));
+                    }
+                    // Locks are only taken to store the result, in the usual parser -> old_tree -> input order
+                    let _parser = resource.parser.lock_recover();
+                    commit_reparse(&resource, tree, None, InputBuffer::from(buffer), &[]);
+                    (atoms::ok(), ast).encode(env)
+                }
+                Err(reason) => (atoms::error(), error_map(env, reason)).encode(env),
+            };
+            (atoms::parse_done(), thread_reader, result).encode(env)
+        });
+    });
+
+    Ok((atoms::ok(), reader))
 }
 
-/// Reset the parser state (clear accumulated input and old tree)
+/// Reply to a {:chunk_request, reader, byte_offset} message from parse_with_callback
+/// The chunk must start at the requested offset; an empty binary signals end of input
 #[rustler::nif]
-fn reset_parser(resource: ResourceArc<ParserResource>) -> Atom {
-    {
-        let mut input = resource.accumulated_input.lock().unwrap();
-        input.clear();
-    }
-    
-    {
-        let mut tree_lock = resource.old_tree.lock().unwrap();
-        *tree_lock = None;
-    }
-    
+fn provide_chunk(reader: ResourceArc<ChunkReader>, chunk: rustler::Binary) -> Atom {
+    // A send error means the parse already finished or gave up; the chunk is simply dropped
+    let _ = reader.sender.lock_recover().send(chunk.as_slice().to_vec());
     atoms::ok()
 }
 
-/// Get the current AST without parsing (from last parse result)
+/// Cancel the parse currently running on a cancellable resource, or the next one if none is
+/// Safe to call from any process since it only touches the atomic flag
 #[rustler::nif]
-fn get_current_ast<'env>(
+fn cancel_parse(resource: ResourceArc<ParserResource>) -> Atom {
+    match resource.cancellation_flag {
+        Some(ref flag) => {
+            flag.store(1, Ordering::Relaxed);
+            atoms::ok()
+        }
+        None => atoms::error(),
+    }
+}
+
+/// Parse incrementally by appending a fragment to accumulated input
+/// Uses tree-sitter's incremental parsing with InputEdit tracking
+#[rustler::nif]
+fn parse_incremental<'env>(
     env: Env<'env>,
     resource: ResourceArc<ParserResource>,
+    fragment: String,
 ) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
-    let tree_lock = resource.old_tree.lock().unwrap();
-    
-    match tree_lock.as_ref() {
-        Some(tree) => {
-            let input = resource.accumulated_input.lock().unwrap();
-            let ast = convert_node_to_map(&tree.root_node(), &input, env);
-            Ok((atoms::ok(), ast))
-        }
-        None => {
-            use rustler::Encoder;
-            Ok((atoms::error(), {
-                let mut map = HashMap::new();
-                map.insert("reason".to_string(), "no_tree".encode(env));
-                map
-            }))
-        }
-    }
+    append_fragment(env, &resource, fragment.as_bytes())
 }
 
-/// Check if current tree has errors
+/// Append a raw binary fragment without a NIF decode error on non-UTF-8 input
+/// The bytes are stored and parsed exactly as sent, so offsets in every result match them;
+/// text fields spanning invalid UTF-8 are decoded lossily and their nodes marked text_valid: false
 #[rustler::nif]
-fn has_errors(resource: ResourceArc<ParserResource>) -> bool {
-    let tree_lock = resource.old_tree.lock().unwrap();
-    match tree_lock.as_ref() {
-        Some(tree) => tree.root_node().has_error(),
-        None => false,
-    }
+fn parse_incremental_bytes<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ParserResource>,
+    fragment: rustler::Binary<'a>,
+) -> NifResult<(Atom, HashMap<String, Term<'a>>)> {
+    append_fragment(env, &resource, fragment.as_slice())
 }
 
-/// Get accumulated input size
+/// Parse after splicing new text over an arbitrary byte range
+/// Replaces accumulated_input[start_byte..old_end_byte] with new_text
 #[rustler::nif]
-fn get_buffer_size(resource: ResourceArc<ParserResource>) -> usize {
-    let input = resource.accumulated_input.lock().unwrap();
-    input.len()
+fn parse_edit<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    start_byte: usize,
+    old_end_byte: usize,
+    new_text: String,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    splice_and_reparse(env, &resource, &mut resource.lock_for_edit(), start_byte, old_end_byte, &new_text)
 }
 
-/// Get accumulated input content
+/// Parse as parse_edit would, splicing the input in place and undoing it afterwards, without storing anything
+/// Reports whether the edit leaves errors (and adds new ones) and the ranges it would change
 #[rustler::nif]
-fn get_accumulated_input(resource: ResourceArc<ParserResource>) -> String {
-    let input = resource.accumulated_input.lock().unwrap();
-    input.clone()
+fn try_parse_edit<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    start_byte: usize,
+    old_end_byte: usize,
+    new_text: String,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    use rustler::Encoder;
+
+    // The splice is made in place and always undone, so the input is never copied
+    let mut parser = resource.parser.lock_recover();
+    let tree_lock = resource.old_tree.lock_recover();
+    let mut input = resource.accumulated_input.lock_recover();
+
+    let (input_edit, removed) = match splice_input(env, &resource, &mut input, start_byte, old_end_byte, &new_text) {
+        Ok(spliced) => spliced,
+        Err(map) => return Ok((atoms::error(), map)),
+    };
+    let old_error_count = tree_lock.as_ref().map_or(0, |tree| tree_stats(tree.root_node()).error_count);
+    let old_tree_option = edited_tree_copy(tree_lock.as_ref(), &[input_edit]);
+
+    let parsed = parse_with_limits(resource.limits(), &mut parser, &input, old_tree_option.as_ref());
+    undo_splices(&mut input, &[input_edit], vec![removed]);
+    drop((parser, tree_lock, input));
+    let new_tree = match parsed {
+        Ok(new_tree) => new_tree,
+        Err(reason) => return Ok((atoms::error(), error_map(env, reason))),
+    };
+
+    let changed_ranges = match old_tree_option {
+        Some(ref old_tree) => merge_ranges(new_tree.changed_ranges(old_tree).collect()),
+        None => Vec::new(),
+    };
+    let error_count = tree_stats(new_tree.root_node()).error_count;
+
+    let mut result = HashMap::new();
+    result.insert("has_errors".to_string(), new_tree.root_node().has_error().encode(env));
+    result.insert("introduces_errors".to_string(), (error_count > old_error_count).encode(env));
+    result.insert("error_count".to_string(), error_count.encode(env));
+    result.insert("changed_ranges".to_string(), changed_range_maps(&changed_ranges, env).encode(env));
+    Ok((atoms::ok(), result))
 }
 
-/// Original synchronous parse function (kept for backward compatibility)
+/// Replace the named node spanning exactly start_byte..end_byte with new_text and reparse
+/// The result adds "node": the node now spanning the replacement, or nil if the text merged into a larger one
 #[rustler::nif]
-fn parse_bash<'env>(env: Env<'env>, content: String) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
-    let mut parser = Parser::new();
-    let bash_language = tree_sitter_bash::LANGUAGE.into();
-    
-    if parser.set_language(&bash_language).is_err() {
-        return Err(Error::Atom("failed_to_set_language"));
-    }
+fn replace_node<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    start_byte: usize,
+    end_byte: usize,
+    new_text: String,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    use rustler::Encoder;
 
-    match parser.parse(&content, None) {
-        Some(tree) => {
-            if tree.root_node().has_error() {
-                Ok((atoms::error(), HashMap::new()))
-            } else {
-                let ast = convert_node_to_map(&tree.root_node(), &content, env);
-                Ok((atoms::ok(), ast))
-            }
-        }
-        None => {
-            Err(Error::Atom("failed_to_parse"))
+    // One critical section, so the node can't change between the check, the splice and the lookup
+    let mut locks = resource.lock_for_edit();
+    let (status, found) = with_locked_tree(env, locks.tree_slot.as_ref(), &locks.input, |tree, _| {
+        match node_with_exact_range(tree, start_byte, end_byte) {
+            Some(_) => Ok((atoms::ok(), HashMap::new())),
+            None => Ok((atoms::error(), error_map(env, "not_found"))),
         }
+    })?;
+    if status != atoms::ok() {
+        return Ok((status, found));
+    }
+
+    let (status, mut result) = splice_and_reparse(env, &resource, &mut locks, start_byte, end_byte, &new_text)?;
+    if status == atoms::ok() {
+        let node = locks
+            .tree_slot
+            .as_ref()
+            .and_then(|tree| node_with_exact_range(tree, start_byte, start_byte + new_text.len()))
+            .map(|node| convert_node_to_map_with(&node, &locks.input, env, &resource.convert_options));
+        result.insert("node".to_string(), node.encode(env));
     }
+    Ok((status, result))
 }
 
-// Helper function to convert tree-sitter node to Elixir map
-fn convert_node_to_map<'env>(
-    node: &tree_sitter::Node,
-    source: &str,
-    env: Env<'env>
-) -> HashMap<String, Term<'env>> {
-    use rustler::Encoder;
-    
-    let mut result = HashMap::new();
-    
-    let start = node.start_position();
-    let end = node.end_position();
-    let text = node.utf8_text(source.as_bytes()).unwrap_or("");
-    
-    // Use "type" to match Elixir typed struct expectations
-    result.insert("type".to_string(), node.kind().encode(env));
-    result.insert("start_row".to_string(), start.row.encode(env));
-    result.insert("start_col".to_string(), start.column.encode(env));
-    result.insert("end_row".to_string(), end.row.encode(env));
-    result.insert("end_col".to_string(), end.column.encode(env));
-    result.insert("text".to_string(), text.encode(env));
-    
-    // Add tree-sitter node metadata flags for error recovery
-    result.insert("is_missing".to_string(), node.is_missing().encode(env));
-    result.insert("is_extra".to_string(), node.is_extra().encode(env));
-    result.insert("is_error".to_string(), node.is_error().encode(env));
-    result.insert("has_error".to_string(), node.has_error().encode(env));
-    
-    // Extract ALL named fields automatically using tree-sitter's field metadata
-    extract_all_node_fields(node, source, &mut result, env);
+/// Rename every variable_name occurrence of old_name (assignments, $x, ${x}, arithmetic) in one batch
+/// Single-quoted strings and quoted heredocs hold no variable_name nodes, so they are left alone;
+/// scope, a {start_byte, end_byte} range, limits the rename to occurrences inside it
+#[rustler::nif]
+fn rename_variable<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    old_name: String,
+    new_name: String,
+    scope: Option<(usize, usize)>,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    use rustler::Encoder;
+
+    let is_name = !new_name.starts_with(|c: char| c.is_ascii_digit())
+        && !new_name.is_empty()
+        && new_name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !is_name {
+        return Ok((atoms::error(), error_map(env, "invalid_name")));
+    }
+
+    // Occurrences are collected and renamed under the same locks, so their offsets stay current
+    let mut locks = resource.lock_for_edit();
+    let mut edits: Vec<(usize, usize, String)> = Vec::new();
+    let (status, collected) = with_locked_tree(env, locks.tree_slot.as_ref(), &locks.input, |tree, input| {
+        let (scope_start, scope_end) = scope.unwrap_or((0, input.len()));
+        walk_tree(tree.root_node(), |node| {
+            if node.end_byte() < scope_start || node.start_byte() > scope_end {
+                return false;
+            }
+            if node.kind() == "variable_name"
+                && node.start_byte() >= scope_start
+                && node.end_byte() <= scope_end
+                && node_text(&node, input) == old_name
+            {
+                edits.push((node.start_byte(), node.end_byte(), new_name.clone()));
+            }
+            true
+        });
+        Ok((atoms::ok(), HashMap::new()))
+    })?;
+    if status != atoms::ok() {
+        return Ok((status, collected));
+    }
+
+    let renamed = edits.len();
+    let (status, mut result) = splice_many_and_reparse(env, &resource, &mut locks, &edits)?;
+    if status == atoms::ok() {
+        result.insert("renamed".to_string(), renamed.encode(env));
+    }
+    Ok((status, result))
+}
+
+/// Apply a batch of non-overlapping {start_byte, old_end_byte, new_text} edits, then reparse once
+/// Offsets refer to the current input; edits are applied from the highest offset down so none shift another
+#[rustler::nif]
+fn parse_edits<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    edits: Vec<(usize, usize, String)>,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    splice_many_and_reparse(env, &resource, &mut resource.lock_for_edit(), &edits)
+}
+
+/// Shared body of parse_edits: validate the whole batch, splice it and reparse once, under the caller's locks
+fn splice_many_and_reparse<'env>(
+    env: Env<'env>,
+    resource: &ParserResource,
+    locks: &mut EditLocks,
+    edits: &[(usize, usize, String)],
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    use rustler::Encoder;
+
+    let EditLocks { parser, tree_slot, input } = locks;
+
+    for (index, (start_byte, old_end_byte, _)) in edits.iter().enumerate() {
+        if start_byte > old_end_byte
+            || *old_end_byte > input.len()
+            || !input.is_char_boundary(*start_byte)
+            || !input.is_char_boundary(*old_end_byte)
+        {
+            let mut map = error_map(env, "out_of_range");
+            map.insert("index".to_string(), index.encode(env));
+            return Ok((atoms::error(), map));
+        }
+    }
+
+    let order = match edit_order(edits) {
+        Ok(order) => order,
+        Err(indices) => {
+            let mut map = error_map(env, "overlapping_edits");
+            map.insert("indices".to_string(), vec![indices.0, indices.1].encode(env));
+            return Ok((atoms::error(), map));
+        }
+    };
+
+    let new_size = edits
+        .iter()
+        .fold(input.len(), |size, (start_byte, old_end_byte, new_text)| size - (old_end_byte - start_byte) + new_text.len());
+    let max_buffer_size = resource.max_buffer_size.load(Ordering::Relaxed);
+    if new_size > max_buffer_size {
+        let mut map = error_map(env, "buffer_overflow");
+        map.insert("current_size".to_string(), input.len().encode(env));
+        map.insert("new_size".to_string(), new_size.encode(env));
+        map.insert("max_size".to_string(), max_buffer_size.encode(env));
+        return Ok((atoms::error(), map));
+    }
+
+    // Highest offset first, so no splice shifts one still to come
+    let (input_edits, removed): (Vec<InputEdit>, Vec<Vec<u8>>) = order
+        .iter()
+        .rev()
+        .map(|&index| {
+            let (start_byte, old_end_byte, ref new_text) = edits[index];
+            apply_splice(input, start_byte, old_end_byte, new_text)
+        })
+        .unzip();
+
+    reparse_in_place(env, resource, parser, tree_slot, input, &input_edits, removed)
+}
+
+/// Indices of edits sorted by (start_byte, old_end_byte), or the indices of two that overlap
+/// An insertion at the start of a deletion sorts (and lands) before it; insertions at the same
+/// offset keep the order given, so the result never depends on how the batch was ordered otherwise
+fn edit_order(edits: &[(usize, usize, String)]) -> Result<Vec<usize>, (usize, usize)> {
+    let mut order: Vec<usize> = (0..edits.len()).collect();
+    order.sort_by_key(|&index| (edits[index].0, edits[index].1));
+    match order.windows(2).find(|pair| edits[pair[1]].0 < edits[pair[0]].1) {
+        Some(pair) => Err((pair[0], pair[1])),
+        None => Ok(order),
+    }
+}
+
+/// Delete the byte range start_byte..end_byte and reparse incrementally
+/// Equivalent to an edit whose new_end_byte equals start_byte
+#[rustler::nif]
+fn delete_range<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    start_byte: usize,
+    end_byte: usize,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    splice_and_reparse(env, &resource, &mut resource.lock_for_edit(), start_byte, end_byte, "")
+}
+
+/// Replace the whole document, reparsing incrementally against the previous tree
+/// The edit is narrowed to the span between the common prefix and suffix of old and new text
+#[rustler::nif]
+fn set_document<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    full_text: String,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    // The span is only valid against the input it was computed from, so it is spliced under the same locks
+    let mut locks = resource.lock_for_edit();
+    let (start_byte, old_end_byte, new_end_byte) = minimal_edit_span(&locks.input, full_text.as_bytes());
+
+    splice_and_reparse(env, &resource, &mut locks, start_byte, old_end_byte, &full_text[start_byte..new_end_byte])
+}
+
+/// Minimal single edit (common prefix and suffix trimmed) that turns old_text into new_text
+/// Positions are tree-sitter points, so the result can be fed straight into parse_edit
+#[rustler::nif]
+fn diff_documents<'env>(env: Env<'env>, old_text: String, new_text: String) -> Term<'env> {
+    use rustler::Encoder;
+
+    let (start_byte, old_end_byte, new_end_byte) = minimal_edit_span(old_text.as_bytes(), new_text.as_bytes());
+    let point = |source: &str, byte_offset: usize| {
+        let point = byte_to_point(source.as_bytes(), byte_offset);
+        (point.row, point.column)
+    };
+
+    let mut positions = HashMap::new();
+    positions.insert("start_position".to_string(), point(&old_text, start_byte));
+    positions.insert("old_end_position".to_string(), point(&old_text, old_end_byte));
+    positions.insert("new_end_position".to_string(), point(&new_text, new_end_byte));
+
+    (atoms::ok(), (start_byte, old_end_byte, new_end_byte, positions)).encode(env)
+}
+
+/// Re-read the changed_ranges reported by the last successful reparse
+/// Empty after a full parse or reset; reads never clear the stored ranges
+#[rustler::nif]
+fn get_last_changed_ranges<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |_, _| {
+        let ranges = changed_range_maps(&resource.last_changed_ranges.lock_recover(), env);
+        Ok((atoms::ok(), ranges.encode(env)))
+    })
+}
+
+/// Bound how long any single parse on this resource may take
+/// A value of 0 disables the timeout
+#[rustler::nif]
+fn set_timeout_micros(resource: ResourceArc<ParserResource>, micros: u64) -> Atom {
+    resource.timeout_micros.store(micros, Ordering::Relaxed);
+    atoms::ok()
+}
+
+/// Change the buffer limit without discarding incremental state
+/// Refuses to shrink below the current input rather than truncating it
+#[rustler::nif]
+fn set_max_buffer_size<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    new_size: usize,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
     
-    result
+    // Hold the input lock so no append can grow the buffer between the check and the store
+    let input = resource.accumulated_input.lock_recover();
+    if input.len() > new_size {
+        let mut map = error_map(env, "buffer_too_large");
+        map.insert("current_size".to_string(), input.len().encode(env));
+        map.insert("max_size".to_string(), new_size.encode(env));
+        return Ok((atoms::error(), map.encode(env)));
+    }
+    resource.max_buffer_size.store(new_size, Ordering::Relaxed);
+    Ok((atoms::ok(), new_size.encode(env)))
 }
 
-fn extract_all_node_fields<'env>(
-    node: &tree_sitter::Node,
-    source: &str,
-    result: &mut HashMap<String, Term<'env>>,
-    env: Env<'env>
-) {
+/// Create a pool with `size` pre-built parsers whose parses are bounded by timeout_micros (0 for none)
+#[rustler::nif]
+fn new_pool(size: usize, timeout_micros: u64) -> NifResult<(Atom, ResourceArc<ParserPool>)> {
+    let mut idle = Vec::with_capacity(size);
+    for _ in 0..size {
+        match new_bash_parser() {
+            Ok(parser) => idle.push(parser),
+            Err(msg) => return Err(Error::Term(Box::new(msg))),
+        }
+    }
+
+    Ok((atoms::ok(), ResourceArc::new(ParserPool { idle: Mutex::new(idle), size, timeout_micros })))
+}
+
+/// Parse content statelessly with a parser checked out from the pool
+/// A new parser is built if every pooled parser is busy; the pool never keeps more than its size
+#[rustler::nif]
+fn pool_parse<'env>(
+    env: Env<'env>,
+    pool: ResourceArc<ParserPool>,
+    content: String,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
     use rustler::Encoder;
-    use std::collections::HashMap as StdHashMap;
+
+    let checked_out = pool.idle.lock_recover().pop();
+    let mut parser = match checked_out {
+        Some(parser) => parser,
+        None => new_bash_parser().map_err(|msg| Error::Term(Box::new(msg)))?,
+    };
+
+    let limits = ParseLimits { timeout_micros: pool.timeout_micros, cancellation_flag: None };
+    let tree = parse_with_limits(limits, &mut parser, content.as_bytes(), None);
+    {
+        let mut idle = pool.idle.lock_recover();
+        if idle.len() < pool.size {
+            idle.push(parser);
+        }
+    }
+
+    match tree {
+        Ok(tree) => {
+            let mut ast = convert_node_to_map(&tree.root_node(), content.as_bytes(), env);
+            if tree.root_node().has_error() {
+                ast.insert("has_errors".to_string(), true.encode(env));
+            }
+            Ok((atoms::ok(), ast))
+        }
+        Err(reason) => Ok((atoms::error(), error_map(env, reason))),
+    }
+}
+
+/// Reset the parser state (clear accumulated input and old tree)
+#[rustler::nif]
+fn reset_parser(resource: ResourceArc<ParserResource>) -> Atom {
+    {
+        let mut input = resource.accumulated_input.lock_recover();
+        input.clear();
+    }
     
-    let mut field_map: StdHashMap<String, Vec<HashMap<String, Term<'env>>>> = StdHashMap::new();
-    let mut unnamed_children: Vec<HashMap<String, Term<'env>>> = Vec::new();
+    {
+        let mut tree_lock = resource.old_tree.lock_recover();
+        *tree_lock = None;
+    }
     
-    // Use cursor to iterate with field names
-    let mut cursor = node.walk();
-    let has_children = cursor.goto_first_child();
+    *resource.last_changed_bytes.lock_recover() = None;
+    resource.last_changed_ranges.lock_recover().clear();
     
-    if has_children {
-        loop {
-            let child = cursor.node();
-            
-            // Skip unnamed nodes (like punctuation)
-            if child.is_named() {
-                // Get field name for this child from cursor
-                if let Some(field_name) = cursor.field_name() {
-                    // Named field
-                    let child_map = convert_node_to_map(&child, source, env);
-                    field_map
-                        .entry(field_name.to_string())
-                        .or_insert_with(Vec::new)
-                        .push(child_map);
-                } else {
-                    // Unnamed child (e.g., children of program node)
-                    let child_map = convert_node_to_map(&child, source, env);
-                    unnamed_children.push(child_map);
+    atoms::ok()
+}
+
+/// Full parse of the accumulated input with no old tree, replacing the stored tree
+/// Recovery path for a tree suspected of drifting from the input; on failure the old tree is kept
+#[rustler::nif]
+fn reparse_from_scratch<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    use rustler::Encoder;
+
+    let mut parser = resource.parser.lock_recover();
+    let mut tree_lock = resource.old_tree.lock_recover();
+    let input = resource.accumulated_input.lock_recover();
+
+    match parse_with_limits(resource.limits(), &mut parser, &input, None) {
+        Ok(new_tree) => {
+            let mut result = reparse_result(env, &resource, &new_tree, None, &input, &[]);
+            // A full parse has no old tree to diff ranges against
+            result.insert("changed_ranges".to_string(), changed_range_maps(&[], env).encode(env));
+            record_reparse(&resource, &mut tree_lock, new_tree, None, input.len(), &[]);
+            Ok((atoms::ok(), result))
+        }
+        Err(reason) => Ok((atoms::error(), error_map(env, reason))),
+    }
+}
+
+/// Get the current AST without parsing (from last parse result)
+#[rustler::nif]
+fn get_current_ast<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    with_tree(env, &resource, |tree, input| {
+        let ast = convert_node_to_map_with(&tree.root_node(), input, env, &resource.convert_options);
+        Ok((atoms::ok(), ast))
+    })
+}
+
+/// Get the current AST, dropping the text of any node longer than max_text_bytes
+/// Such nodes carry text: nil, text_truncated: true and their start_byte/end_byte
+#[rustler::nif(name = "get_current_ast")]
+fn get_current_ast_text_limited<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    max_text_bytes: usize,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    with_tree(env, &resource, |tree, input| {
+        let options = ConvertOptions { max_text_bytes: Some(max_text_bytes), ..resource.convert_options };
+        let ast = convert_node_to_map_with(&tree.root_node(), input, env, &options);
+        Ok((atoms::ok(), ast))
+    })
+}
+
+/// Get the current AST with columns measured in UTF-16 code units
+/// Matches the position encoding LSP clients expect
+#[rustler::nif]
+fn get_current_ast_utf16<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    with_tree(env, &resource, |tree, input| {
+        let options = ConvertOptions { utf16_columns: true, ..resource.convert_options };
+        let ast = convert_node_to_map_with(&tree.root_node(), input, env, &options);
+        Ok((atoms::ok(), ast))
+    })
+}
+
+/// Get the current AST converted at most max_depth levels deep
+/// Nodes at the cutoff carry "truncated" and "child_count" for lazy expansion
+#[rustler::nif]
+fn get_current_ast_shallow<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    max_depth: usize,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    with_tree(env, &resource, |tree, input| {
+        let options = ConvertOptions { max_depth: Some(max_depth), ..resource.convert_options };
+        let ast = convert_node_to_map_with(&tree.root_node(), input, env, &options);
+        Ok((atoms::ok(), ast))
+    })
+}
+
+/// Check if current tree has errors
+#[rustler::nif]
+fn has_errors(resource: ResourceArc<ParserResource>) -> bool {
+    let tree_lock = resource.old_tree.lock_recover();
+    match tree_lock.as_ref() {
+        Some(tree) => tree.root_node().has_error(),
+        None => false,
+    }
+}
+
+/// Get accumulated input size
+#[rustler::nif]
+fn get_buffer_size(resource: ResourceArc<ParserResource>) -> usize {
+    let input = resource.accumulated_input.lock_recover();
+    input.len()
+}
+
+/// Get accumulated input content, as the raw bytes it was built from
+#[rustler::nif]
+fn get_accumulated_input<'env>(env: Env<'env>, resource: ResourceArc<ParserResource>) -> Term<'env> {
+    let input = resource.accumulated_input.lock_recover();
+    encode_bytes(env, &input)
+}
+
+/// Get a slice of accumulated input without copying the whole buffer
+/// The range must lie within the input and on UTF-8 character boundaries
+#[rustler::nif]
+fn get_input_range<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    start_byte: usize,
+    end_byte: usize,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    let input = resource.accumulated_input.lock_recover();
+    match input_range(&input, start_byte, end_byte) {
+        Some(slice) => Ok((atoms::ok(), encode_bytes(env, slice))),
+        None => Ok((atoms::error(), error_map(env, "out_of_range").encode(env))),
+    }
+}
+
+/// Bytes of start_byte..end_byte, or None unless the range lies in the input and on character boundaries
+fn input_range(input: &InputBuffer, start_byte: usize, end_byte: usize) -> Option<&[u8]> {
+    let in_range = start_byte <= end_byte && input.is_char_boundary(start_byte) && input.is_char_boundary(end_byte);
+    in_range.then(|| &input[start_byte..end_byte])
+}
+
+/// Source text of a byte range plus context_lines of surrounding lines, for diagnostic snippets
+/// Lines the range covers carry a {start_col, end_col} "marker" (byte columns); others have nil
+#[rustler::nif]
+fn node_source_context<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    start_byte: usize,
+    end_byte: usize,
+    context_lines: usize,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    let input = resource.accumulated_input.lock_recover();
+    let text = match input_range(&input, start_byte, end_byte) {
+        Some(text) => text,
+        None => return Ok((atoms::error(), error_map(env, "out_of_range").encode(env))),
+    };
+
+    let start = input.byte_to_point(start_byte);
+    let end = input.byte_to_point(end_byte);
+    let first_row = start.row.saturating_sub(context_lines);
+    let last_row = end.row.saturating_add(context_lines).min(input.line_count() - 1);
+
+    let lines: Vec<HashMap<String, Term<'env>>> = (first_row..=last_row)
+        .filter_map(|row| input.line(row).map(|line| (row, line)))
+        .map(|(row, line)| {
+            let marker = (start.row..=end.row).contains(&row).then(|| {
+                let marker_start = if row == start.row { start.column } else { 0 };
+                let marker_end = if row == end.row { end.column } else { line.len() };
+                (marker_start, marker_end)
+            });
+            let mut map = HashMap::new();
+            map.insert("row".to_string(), row.encode(env));
+            let trailing_returns = line.iter().rev().take_while(|&&byte| byte == b'\r').count();
+            map.insert("text".to_string(), encode_bytes(env, &line[..line.len() - trailing_returns]));
+            map.insert("marker".to_string(), marker.encode(env));
+            map
+        })
+        .collect();
+
+    let mut result = HashMap::new();
+    result.insert("text".to_string(), encode_bytes(env, text));
+    result.insert("start_row".to_string(), start.row.encode(env));
+    result.insert("end_row".to_string(), end.row.encode(env));
+    result.insert("lines".to_string(), lines.encode(env));
+    Ok((atoms::ok(), result.encode(env)))
+}
+
+/// Count lines in accumulated input (newlines + 1, so empty input is one line)
+#[rustler::nif]
+fn get_line_count(resource: ResourceArc<ParserResource>) -> usize {
+    let input = resource.accumulated_input.lock_recover();
+    input.line_count()
+}
+
+/// Byte offset at which the given zero-based line starts
+#[rustler::nif]
+fn get_byte_offset_for_line<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    row: usize,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    let input = resource.accumulated_input.lock_recover();
+    match input.point_to_byte(row, 0) {
+        Some(byte_offset) => Ok((atoms::ok(), byte_offset.encode(env))),
+        None => Ok((atoms::error(), error_map(env, "out_of_range").encode(env))),
+    }
+}
+
+/// Original synchronous parse function (kept for backward compatibility)
+#[rustler::nif]
+fn parse_bash<'env>(env: Env<'env>, content: String) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    let mut parser = new_bash_parser().map_err(|err| Error::Term(Box::new(err)))?;
+
+    match parser.parse(&content, None) {
+        Some(tree) => {
+            if tree.root_node().has_error() {
+                Ok((atoms::error(), HashMap::new()))
+            } else {
+                let ast = convert_node_to_map(&tree.root_node(), content.as_bytes(), env);
+                Ok((atoms::ok(), ast))
+            }
+        }
+        None => {
+            Err(Error::Atom("failed_to_parse"))
+        }
+    }
+}
+
+/// Stateless parse that keeps tree-sitter's error-recovered AST
+/// Unlike parse_bash, syntax errors only add "has_errors": true to the root map
+#[rustler::nif]
+fn parse_bash_lenient<'env>(env: Env<'env>, content: String) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    use rustler::Encoder;
+
+    let mut parser = new_bash_parser().map_err(|msg| Error::Term(Box::new(msg)))?;
+    match parser.parse(&content, None) {
+        Some(tree) => {
+            let mut ast = convert_node_to_map(&tree.root_node(), content.as_bytes(), env);
+            if tree.root_node().has_error() {
+                ast.insert("has_errors".to_string(), true.encode(env));
+            }
+            Ok((atoms::ok(), ast))
+        }
+        None => Err(Error::Atom("failed_to_parse")),
+    }
+}
+
+/// Run a tree-sitter S-expression query against the current tree
+/// Text predicates (#eq?, #match?, #any-of? and their not- forms) filter matches
+/// byte_range restricts matching to a {start_byte, end_byte} region; match_limit caps in-progress matches
+#[rustler::nif]
+fn run_query<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    query_string: String,
+    byte_range: Option<(usize, usize)>,
+    match_limit: Option<u32>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    if byte_range.is_some_and(|(start_byte, end_byte)| start_byte > end_byte) {
+        return Ok((atoms::error(), error_map(env, "out_of_range").encode(env)));
+    }
+    if match_limit == Some(0) {
+        return Ok((atoms::error(), error_map(env, "invalid_match_limit").encode(env)));
+    }
+
+    let query = match compile_query(&query_string) {
+        Ok(query) => query,
+        Err(err) => return Ok((atoms::error(), query_error_map(&err, env).encode(env))),
+    };
+
+    with_tree(env, &resource, |tree, input| {
+        let capture_names = query.capture_names();
+        let mut cursor = QueryCursor::new();
+        if let Some((start_byte, end_byte)) = byte_range {
+            cursor.set_byte_range(start_byte..end_byte);
+        }
+        if let Some(match_limit) = match_limit {
+            cursor.set_match_limit(match_limit);
+        }
+        let mut captures = cursor.captures(&query, tree.root_node(), &**input);
+
+        let mut results = Vec::new();
+        while let Some((query_match, capture_index)) = captures.next() {
+            let capture = query_match.captures[*capture_index];
+            let mut map = HashMap::new();
+            map.insert("capture_name".to_string(), capture_names[capture.index as usize].encode(env));
+            map.insert("pattern_index".to_string(), query_match.pattern_index.encode(env));
+            map.insert("start_byte".to_string(), capture.node.start_byte().encode(env));
+            map.insert("end_byte".to_string(), capture.node.end_byte().encode(env));
+            map.insert("node".to_string(), convert_node_to_map(&capture.node, input, env).encode(env));
+            results.push(map);
+        }
+
+        Ok((atoms::ok(), results.encode(env)))
+    })
+}
+
+/// Find the smallest named node containing a row/column position
+/// Position is validated against accumulated input before the lookup
+#[rustler::nif]
+fn node_at_position<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    row: usize,
+    col: usize,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    with_tree(env, &resource, |tree, input| {
+        match named_node_at_point(tree, input, row, col) {
+            Some(node) => Ok((atoms::ok(), convert_node_to_map(&node, input, env))),
+            None => Ok((atoms::error(), error_map(env, "out_of_range"))),
+        }
+    })
+}
+
+/// Shallow info for the smallest named node at a row/column position
+/// Reports child counts instead of converting the subtree
+#[rustler::nif]
+fn node_info_at<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    row: usize,
+    col: usize,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        match named_node_at_point(tree, input, row, col) {
+            Some(node) => {
+                let mut map = position_map(&node, env);
+                map.insert("type".to_string(), node.kind().encode(env));
+                map.insert("named_child_count".to_string(), node.named_child_count().encode(env));
+                map.insert("child_count".to_string(), node.child_count().encode(env));
+                Ok((atoms::ok(), map))
+            }
+            None => Ok((atoms::error(), error_map(env, "out_of_range"))),
+        }
+    })
+}
+
+/// Ancestor chain of the smallest named node at a row/column position
+/// Ordered innermost first: the node itself, then each parent up to the root
+#[rustler::nif]
+fn ancestors_at<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    row: usize,
+    col: usize,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let node = match named_node_at_point(tree, input, row, col) {
+            Some(node) => node,
+            None => return Ok((atoms::error(), error_map(env, "out_of_range").encode(env))),
+        };
+
+        let chain: Vec<HashMap<String, Term<'env>>> = std::iter::successors(Some(node), |node| node.parent())
+            .map(|ancestor| {
+                let mut map = HashMap::new();
+                map.insert("type".to_string(), ancestor.kind().encode(env));
+                map.insert("start_byte".to_string(), ancestor.start_byte().encode(env));
+                map.insert("end_byte".to_string(), ancestor.end_byte().encode(env));
+                map
+            })
+            .collect();
+
+        Ok((atoms::ok(), chain.encode(env)))
+    })
+}
+
+/// Child indices from the root down to the smallest named node at a row/column position
+/// Indices count every child (anonymous tokens included), matching node_at_path
+#[rustler::nif]
+fn node_path_at<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    row: usize,
+    col: usize,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let node = match named_node_at_point(tree, input, row, col) {
+            Some(node) => node,
+            None => return Ok((atoms::error(), error_map(env, "out_of_range").encode(env))),
+        };
+
+        let mut path: Vec<usize> = std::iter::successors(Some(node), |node| node.parent())
+            .filter_map(|node| {
+                let parent = node.parent()?;
+                let mut cursor = parent.walk();
+                let index = parent.children(&mut cursor).position(|child| child.id() == node.id());
+                index
+            })
+            .collect();
+        path.reverse();
+
+        Ok((atoms::ok(), path.encode(env)))
+    })
+}
+
+/// Node reached by following child indices down from the root (the inverse of node_path_at)
+/// An index past a node's children gives {:error, invalid_path} with its position in the path
+#[rustler::nif]
+fn node_at_path<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    path: Vec<usize>,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut node = tree.root_node();
+        for (depth, &index) in path.iter().enumerate() {
+            node = match node.child(index) {
+                Some(child) => child,
+                None => {
+                    let mut map = error_map(env, "invalid_path");
+                    map.insert("index".to_string(), depth.encode(env));
+                    return Ok((atoms::error(), map));
+                }
+            };
+        }
+
+        Ok((atoms::ok(), convert_node_to_map_with(&node, input, env, &resource.convert_options)))
+    })
+}
+
+/// Next named sibling of the node spanning exactly start_byte..end_byte
+/// Returns {:ok, nil} at the last sibling, {:error, not_found} if no node has that range
+#[rustler::nif]
+fn next_sibling<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    start_byte: usize,
+    end_byte: usize,
+) -> NifResult<(Atom, Term<'env>)> {
+    named_sibling_of_range(env, &resource, start_byte, end_byte, |node| node.next_named_sibling())
+}
+
+/// Previous named sibling of the node spanning exactly start_byte..end_byte
+/// Returns {:ok, nil} at the first sibling, {:error, not_found} if no node has that range
+#[rustler::nif]
+fn prev_sibling<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    start_byte: usize,
+    end_byte: usize,
+) -> NifResult<(Atom, Term<'env>)> {
+    named_sibling_of_range(env, &resource, start_byte, end_byte, |node| node.prev_named_sibling())
+}
+
+/// Shared body of next_sibling/prev_sibling
+fn named_sibling_of_range<'env, F>(
+    env: Env<'env>,
+    resource: &ParserResource,
+    start_byte: usize,
+    end_byte: usize,
+    sibling: F,
+) -> NifResult<(Atom, Term<'env>)>
+where
+    F: for<'tree> Fn(&tree_sitter::Node<'tree>) -> Option<tree_sitter::Node<'tree>>,
+{
+    use rustler::Encoder;
+
+    with_tree(env, resource, |tree, _| {
+        let node = match node_with_exact_range(tree, start_byte, end_byte) {
+            Some(node) => node,
+            None => return Ok((atoms::error(), error_map(env, "not_found").encode(env))),
+        };
+
+        let sibling = sibling(&node).map(|sibling| {
+            let mut map = position_map(&sibling, env);
+            map.insert("type".to_string(), sibling.kind().encode(env));
+            map
+        });
+        Ok((atoms::ok(), sibling.encode(env)))
+    })
+}
+
+/// Structural partner of the delimiter token at byte_offset (brackets, quotes, do/done, if/fi, case/esac)
+/// Returns {:ok, nil} when the offset is not on a delimiter or its partner is missing
+#[rustler::nif]
+fn matching_delimiter<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    byte_offset: usize,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        if byte_offset >= input.len() {
+            return Ok((atoms::error(), error_map(env, "out_of_range").encode(env)));
+        }
+
+        let token = match tree.root_node().descendant_for_byte_range(byte_offset, byte_offset + 1) {
+            Some(token) if token.child_count() == 0 => token,
+            _ => return Ok((atoms::ok(), rustler::types::atom::nil().encode(env))),
+        };
+
+        // A raw string is a single leaf, so its quotes are matched by position
+        if token.kind() == "raw_string" {
+            let partner_byte = if byte_offset == token.start_byte() {
+                token.end_byte() - 1
+            } else if byte_offset == token.end_byte() - 1 {
+                token.start_byte()
+            } else {
+                return Ok((atoms::ok(), rustler::types::atom::nil().encode(env)));
+            };
+            let start = input.byte_to_point(partner_byte);
+            let end = input.byte_to_point(partner_byte + 1);
+            let mut map = HashMap::new();
+            map.insert("type".to_string(), "'".encode(env));
+            map.insert("start_byte".to_string(), partner_byte.encode(env));
+            map.insert("end_byte".to_string(), (partner_byte + 1).encode(env));
+            map.insert("start_row".to_string(), start.row.encode(env));
+            map.insert("start_col".to_string(), start.column.encode(env));
+            map.insert("end_row".to_string(), end.row.encode(env));
+            map.insert("end_col".to_string(), end.column.encode(env));
+            return Ok((atoms::ok(), map.encode(env)));
+        }
+
+        let partner = delimiter_partner(&token).map(|partner| {
+            let mut map = position_map(&partner, env);
+            map.insert("type".to_string(), partner.kind().encode(env));
+            map
+        });
+        Ok((atoms::ok(), partner.encode(env)))
+    })
+}
+
+/// Whether accumulated input ends in a complete statement (PS1) or needs more lines (PS2)
+/// Incomplete means an open quote/block, a trailing `\` continuation, or an unfinished heredoc
+#[rustler::nif]
+fn is_complete(resource: ResourceArc<ParserResource>) -> bool {
+    let tree_lock = resource.old_tree.lock_recover();
+    let input = resource.accumulated_input.lock_recover();
+
+    match tree_lock.as_ref() {
+        Some(tree) => input_is_complete(tree, &input),
+        None => input.iter().all(u8::is_ascii_whitespace),
+    }
+}
+
+/// Edits for an append, as built by prepare_append
+struct PreparedAppend<'env> {
+    input_edits: Vec<InputEdit>,
+    /// Leading bytes dropped by windowing or auto-flush
+    evicted_bytes: usize,
+    /// Statements flushed to make room (auto-flush mode only)
+    flushed: Option<Vec<HashMap<String, Term<'env>>>>,
+}
+
+/// Build the InputEdits for appending fragment to `current`, the stored input
+/// Checks the buffer limit and plans windowed eviction or auto-flush; errors are returned as a reason map
+fn prepare_append<'env>(
+    env: Env<'env>,
+    resource: &ParserResource,
+    old_tree: Option<&Tree>,
+    current: &InputBuffer,
+    fragment: &[u8],
+) -> Result<PreparedAppend<'env>, HashMap<String, Term<'env>>> {
+    use rustler::Encoder;
+    
+    let max_buffer_size = resource.max_buffer_size.load(Ordering::Relaxed);
+    
+    let old_len = current.len();
+    
+    // In auto-flush mode an overflow first drops every complete leading statement
+    let mut flushed = None;
+    let mut flushed_bytes = 0;
+    if resource.auto_flush && old_len + fragment.len() > max_buffer_size {
+        if let Some(old_tree) = old_tree {
+            let (cut, statements) = flushable_statements(env, resource, old_tree, current);
+            flushed_bytes = cut;
+            flushed = Some(statements);
+        }
+    }
+    
+    // Check buffer size before appending
+    if old_len - flushed_bytes + fragment.len() > max_buffer_size {
+        let mut map = error_map(env, "buffer_overflow");
+        map.insert("current_size".to_string(), old_len.encode(env));
+        map.insert("fragment_size".to_string(), fragment.len().encode(env));
+        map.insert("max_size".to_string(), max_buffer_size.encode(env));
+        return Err(map);
+    }
+    
+    let new_len = old_len + fragment.len();
+    let end_position = current.byte_to_point(old_len);
+    // Only the fragment is scanned; the stored input's position comes from its line index
+    let new_end_position = match fragment.iter().rposition(|&byte| byte == b'\n') {
+        Some(index) => Point {
+            row: end_position.row + newline_ends(fragment, 0).count(),
+            column: fragment.len() - index - 1,
+        },
+        None => Point {
+            row: end_position.row,
+            column: end_position.column + fragment.len(),
+        },
+    };
+    
+    // Create InputEdit for tree-sitter's incremental parsing
+    let input_edit = InputEdit {
+        start_byte: old_len,
+        old_end_byte: old_len,
+        new_end_byte: new_len,
+        start_position: end_position,
+        old_end_position: end_position,
+        new_end_position,
+    };
+    
+    let mut input_edits = vec![input_edit];
+    
+    // In windowed mode, drop the oldest complete statements once the window is exceeded
+    let mut evicted_bytes = flushed_bytes;
+    if let (Some(window_bytes), 0) = (resource.window_bytes, flushed_bytes) {
+        if new_len > window_bytes {
+            if let Some(old_tree) = old_tree {
+                evicted_bytes = eviction_point(old_tree, new_len - window_bytes);
+            }
+        }
+    }
+    
+    // Evicted statements always lie in the stored input, so their end position comes from `current`
+    if evicted_bytes > 0 {
+        let old_end_position = current.byte_to_point(evicted_bytes);
+        input_edits.push(InputEdit {
+            start_byte: 0,
+            old_end_byte: evicted_bytes,
+            new_end_byte: 0,
+            start_position: Point::default(),
+            old_end_position,
+            new_end_position: Point::default(),
+        });
+    }
+    
+    Ok(PreparedAppend { input_edits, evicted_bytes, flushed })
+}
+
+/// Append fragment and reparse, adding eviction/flush details to the usual reparse result
+/// Shared by parse_incremental and parse_incremental_bytes
+fn append_fragment<'env>(
+    env: Env<'env>,
+    resource: &ParserResource,
+    fragment: &[u8],
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    use rustler::Encoder;
+    
+    let appended = append_and_reparse(env, resource, fragment, |input, new_tree, old_tree, input_edits| {
+        let started = Instant::now();
+        let result = reparse_result(env, resource, new_tree, old_tree, input, input_edits);
+        (result, started.elapsed())
+    });
+    let ((mut result, convert_time), prepared, parse_time) = match appended {
+        Ok(appended) => appended,
+        Err(map) => return Ok((atoms::error(), map)),
+    };
+    
+    if resource.include_timing {
+        insert_timing(env, &mut result, parse_time, convert_time);
+    }
+    let changed_ranges = changed_range_maps(&resource.last_changed_ranges.lock_recover(), env);
+    result.insert("changed_ranges".to_string(), changed_ranges.encode(env));
+    if resource.window_bytes.is_some() {
+        result.insert("evicted_bytes".to_string(), prepared.evicted_bytes.encode(env));
+    }
+    if let Some(flushed) = prepared.flushed {
+        result.insert("flushed".to_string(), flushed.encode(env));
+        result.insert("flushed_bytes".to_string(), prepared.evicted_bytes.encode(env));
+    }
+    Ok((atoms::ok(), result))
+}
+
+/// Append fragment to the stored input in place and reparse, without copying the whole buffer
+/// All three locks are held throughout so no other call sees a half-applied append, and a
+/// failed parse undoes it; `finish` runs on the appended input before the new tree is stored.
+/// Also returns the time spent in tree-sitter's parse
+fn append_and_reparse<'env, R, F>(
+    env: Env<'env>,
+    resource: &ParserResource,
+    fragment: &[u8],
+    finish: F,
+) -> Result<(R, PreparedAppend<'env>, Duration), HashMap<String, Term<'env>>>
+where
+    F: FnOnce(&[u8], &Tree, Option<&Tree>, &[InputEdit]) -> R,
+{
+    // Parser, then tree, then input: the order every other path takes these locks in
+    let mut parser = resource.parser.lock_recover();
+    let mut tree_lock = resource.old_tree.lock_recover();
+    let mut input = resource.accumulated_input.lock_recover();
+    
+    let prepared = prepare_append(env, resource, tree_lock.as_ref(), &input, fragment)?;
+    let old_tree = edited_tree_copy(tree_lock.as_ref(), &prepared.input_edits);
+    
+    // Only the evicted prefix is set aside, to restore it if the parse fails
+    input.push_bytes(fragment);
+    let evicted = input[..prepared.evicted_bytes].to_vec();
+    input.replace_range(0..prepared.evicted_bytes, b"");
+    
+    let parse_started = Instant::now();
+    let new_tree = match parse_with_limits(resource.limits(), &mut parser, &input, old_tree.as_ref()) {
+        Ok(new_tree) => new_tree,
+        Err(reason) => {
+            let appended_at = input.len() - fragment.len();
+            input.truncate(appended_at);
+            input.replace_range(0..0, &evicted);
+            return Err(error_map(env, reason));
+        }
+    };
+    
+    let parse_time = parse_started.elapsed();
+    
+    let result = finish(&input, &new_tree, old_tree.as_ref(), &prepared.input_edits);
+    record_reparse(resource, &mut tree_lock, new_tree, old_tree.as_ref(), input.len(), &prepared.input_edits);
+    Ok((result, prepared, parse_time))
+}
+
+/// Complete leading statements that auto-flush can drop, converted before they are evicted
+/// Returns the byte count to cut (start of the trailing statement) and the statement maps
+fn flushable_statements<'env>(
+    env: Env<'env>,
+    resource: &ParserResource,
+    old_tree: &Tree,
+    input: &[u8],
+) -> (usize, Vec<HashMap<String, Term<'env>>>) {
+    let cut = eviction_point(old_tree, usize::MAX);
+    let root = old_tree.root_node();
+    let mut cursor = root.walk();
+    let statements = root
+        .named_children(&mut cursor)
+        .take_while(|child| child.end_byte() <= cut)
+        .map(|child| convert_node_to_map_with(&child, input, env, &resource.convert_options))
+        .collect();
+    (cut, statements)
+}
+
+/// Byte span (start, old_end, new_end) that differs between old and new text
+/// Both ends are kept on char boundaries so the span can be sliced from either string
+fn minimal_edit_span(old: &[u8], new: &[u8]) -> (usize, usize, usize) {
+    let at_boundary = |old_offset: usize, new_offset: usize| is_char_boundary(old, old_offset) && is_char_boundary(new, new_offset);
+
+    let mut prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    while !at_boundary(prefix, prefix) {
+        prefix -= 1;
+    }
+
+    let max_suffix = old.len().min(new.len()) - prefix;
+    let mut suffix = old
+        .iter()
+        .rev()
+        .zip(new.iter().rev())
+        .take(max_suffix)
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !at_boundary(old.len() - suffix, new.len() - suffix) {
+        suffix -= 1;
+    }
+
+    (prefix, old.len() - suffix, new.len() - suffix)
+}
+
+/// Splice new_text over start_byte..old_end_byte in accumulated input and reparse, under the caller's locks
+/// Validates the range and buffer limit before touching any state
+fn splice_and_reparse<'env>(
+    env: Env<'env>,
+    resource: &ParserResource,
+    locks: &mut EditLocks,
+    start_byte: usize,
+    old_end_byte: usize,
+    new_text: &str,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    match splice_input(env, resource, &mut locks.input, start_byte, old_end_byte, new_text) {
+        Ok((input_edit, removed)) => reparse_in_place(
+            env,
+            resource,
+            &mut locks.parser,
+            &mut locks.tree_slot,
+            &mut locks.input,
+            &[input_edit],
+            vec![removed],
+        ),
+        Err(map) => Ok((atoms::error(), map)),
+    }
+}
+
+/// Splice new_text over start_byte..old_end_byte of the (locked) input in place
+/// Returns the InputEdit and the replaced text for undo_splices; errors (out_of_range,
+/// buffer_overflow) are returned as a reason map before anything is changed
+fn splice_input<'env>(
+    env: Env<'env>,
+    resource: &ParserResource,
+    input: &mut InputBuffer,
+    start_byte: usize,
+    old_end_byte: usize,
+    new_text: &str,
+) -> Result<(InputEdit, Vec<u8>), HashMap<String, Term<'env>>> {
+    use rustler::Encoder;
+
+    if start_byte > old_end_byte
+        || old_end_byte > input.len()
+        || !input.is_char_boundary(start_byte)
+        || !input.is_char_boundary(old_end_byte)
+    {
+        return Err(error_map(env, "out_of_range"));
+    }
+
+    let new_size = input.len() - (old_end_byte - start_byte) + new_text.len();
+    let max_buffer_size = resource.max_buffer_size.load(Ordering::Relaxed);
+    if new_size > max_buffer_size {
+        let mut map = error_map(env, "buffer_overflow");
+        map.insert("current_size".to_string(), input.len().encode(env));
+        map.insert("fragment_size".to_string(), new_text.len().encode(env));
+        map.insert("max_size".to_string(), max_buffer_size.encode(env));
+        return Err(map);
+    }
+
+    Ok(apply_splice(input, start_byte, old_end_byte, new_text))
+}
+
+/// Replace start_byte..old_end_byte with new_text, returning its InputEdit and the replaced text
+/// The range must already be validated
+fn apply_splice(input: &mut InputBuffer, start_byte: usize, old_end_byte: usize, new_text: &str) -> (InputEdit, Vec<u8>) {
+    // Positions must be computed before and after the splice respectively
+    let start_position = input.byte_to_point(start_byte);
+    let old_end_position = input.byte_to_point(old_end_byte);
+    let removed = input[start_byte..old_end_byte].to_vec();
+    input.replace_range(start_byte..old_end_byte, new_text.as_bytes());
+    let new_end_byte = start_byte + new_text.len();
+    let new_end_position = input.byte_to_point(new_end_byte);
+
+    let input_edit = InputEdit {
+        start_byte,
+        old_end_byte,
+        new_end_byte,
+        start_position,
+        old_end_position,
+        new_end_position,
+    };
+
+    (input_edit, removed)
+}
+
+/// Revert splices made by apply_splice, given in the order they were applied
+fn undo_splices(input: &mut InputBuffer, input_edits: &[InputEdit], removed: Vec<Vec<u8>>) {
+    for (input_edit, text) in input_edits.iter().zip(removed).rev() {
+        input.replace_range(input_edit.start_byte..input_edit.new_end_byte, &text);
+    }
+}
+
+/// Reparse input already edited in place by input_edits (in order), under the caller's locks
+/// The new tree is only stored if the parse succeeds; otherwise the splices are undone
+fn reparse_in_place<'env>(
+    env: Env<'env>,
+    resource: &ParserResource,
+    parser: &mut Parser,
+    tree_slot: &mut Option<Tree>,
+    input: &mut InputBuffer,
+    input_edits: &[InputEdit],
+    removed: Vec<Vec<u8>>,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    use rustler::Encoder;
+    
+    let old_tree_option = edited_tree_copy(tree_slot.as_ref(), input_edits);
+    
+    // Parse with old_tree as reference (tree-sitter reuses unchanged subtrees internally)
+    let parse_started = Instant::now();
+    match parse_with_limits(resource.limits(), parser, input, old_tree_option.as_ref()) {
+        Ok(new_tree) => {
+            let parse_time = parse_started.elapsed();
+            let convert_started = Instant::now();
+            let mut result = reparse_result(env, resource, &new_tree, old_tree_option.as_ref(), input, input_edits);
+            if resource.include_timing {
+                insert_timing(env, &mut result, parse_time, convert_started.elapsed());
+            }
+            
+            record_reparse(resource, tree_slot, new_tree, old_tree_option.as_ref(), input.len(), input_edits);
+            let changed_ranges = changed_range_maps(&resource.last_changed_ranges.lock_recover(), env);
+            result.insert("changed_ranges".to_string(), changed_ranges.encode(env));
+            
+            Ok((atoms::ok(), result))
+        }
+        Err(reason) => {
+            undo_splices(input, input_edits, removed);
+            Ok((atoms::error(), error_map(env, reason)))
+        }
+    }
+}
+
+/// Add parse_micros (tree-sitter) and convert_micros (building the Elixir terms) to a result map
+fn insert_timing<'env>(
+    env: Env<'env>,
+    result: &mut HashMap<String, Term<'env>>,
+    parse_time: Duration,
+    convert_time: Duration,
+) {
+    use rustler::Encoder;
+    
+    result.insert("parse_micros".to_string(), (parse_time.as_micros() as u64).encode(env));
+    result.insert("convert_micros".to_string(), (convert_time.as_micros() as u64).encode(env));
+}
+
+/// AST of a successful reparse plus its change metadata, all but changed_ranges
+/// (those are read back from the resource once the reparse is committed)
+fn reparse_result<'env>(
+    env: Env<'env>,
+    resource: &ParserResource,
+    new_tree: &Tree,
+    old_tree: Option<&Tree>,
+    input: &[u8],
+    input_edits: &[InputEdit],
+) -> HashMap<String, Term<'env>> {
+    use rustler::Encoder;
+    
+    let mut result = convert_node_to_map_with(&new_tree.root_node(), input, env, &resource.convert_options);
+    if new_tree.root_node().has_error() {
+        result.insert("has_errors".to_string(), true.encode(env));
+    }
+    
+    let changed_nodes = changed_node_maps(new_tree, old_tree, input, env, &resource.convert_options, resource.statement_container);
+    let reparsed_bytes = reparsed_byte_count(new_tree, old_tree, input.len(), input_edits);
+    // Text-only edits (e.g. whitespace inside a string) leave the structure untouched
+    let statement_kinds = |tree: &Tree| {
+        let container = statement_container(tree, resource.statement_container);
+        let mut cursor = container.walk();
+        container.named_children(&mut cursor).map(|child| child.kind_id()).collect::<Vec<_>>()
+    };
+    let has_changes = old_tree.is_none_or(|old_tree| {
+        new_tree.changed_ranges(old_tree).next().is_some() || statement_kinds(old_tree) != statement_kinds(new_tree)
+    });
+    
+    result.insert("has_changes".to_string(), has_changes.encode(env));
+    result.insert("changed_nodes".to_string(), changed_nodes.encode(env));
+    result.insert("reparsed_bytes".to_string(), reparsed_bytes.encode(env));
+    result.insert("reused_bytes".to_string(), (input.len() - reparsed_bytes).encode(env));
+    result
+}
+
+/// Copy the (locked) stored tree and apply the pending edits to it (required for incremental parsing)
+/// Working on a copy means a failed or cancelled parse leaves the stored tree untouched
+fn edited_tree_copy(tree: Option<&Tree>, input_edits: &[InputEdit]) -> Option<Tree> {
+    let mut old_tree_option = tree.cloned();
+    if let Some(ref mut old_tree) = old_tree_option {
+        for input_edit in input_edits {
+            old_tree.edit(input_edit);
+        }
+    }
+    old_tree_option
+}
+
+/// Changed nodes after a reparse; on a first parse every top-level named node is new
+/// "Top-level" means the named children of statement_container(new_tree, container)
+fn changed_node_maps<'env>(
+    new_tree: &Tree,
+    old_tree: Option<&Tree>,
+    source: &[u8],
+    env: Env<'env>,
+    options: &ConvertOptions,
+    container: Option<&str>,
+) -> Vec<HashMap<String, Term<'env>>> {
+    if let Some(old_tree) = old_tree {
+        return extract_changed_nodes(new_tree, old_tree, source, env, options, container);
+    }
+
+    let root = statement_container(new_tree, container);
+    let mut cursor = root.walk();
+    let children: Vec<_> = root.named_children(&mut cursor).collect();
+    children
+        .iter()
+        .map(|child| convert_node_to_map_with(child, source, env, options))
+        .collect()
+}
+
+/// Store a successfully parsed tree together with the input it was parsed from
+/// Also records the touched byte span so highlight_changed can stay local, and the changed ranges
+fn commit_reparse(
+    resource: &ParserResource,
+    new_tree: Tree,
+    old_tree: Option<&Tree>,
+    input: InputBuffer,
+    input_edits: &[InputEdit],
+) {
+    let mut tree_lock = resource.old_tree.lock_recover();
+    record_reparse(resource, &mut tree_lock, new_tree, old_tree, input.len(), input_edits);
+    *resource.accumulated_input.lock_recover() = input;
+}
+
+/// Store a new tree in the (already locked) tree slot along with its change tracking
+/// In-place appends call this directly, as their input is already updated
+fn record_reparse(
+    resource: &ParserResource,
+    tree_slot: &mut Option<Tree>,
+    new_tree: Tree,
+    old_tree: Option<&Tree>,
+    input_len: usize,
+    input_edits: &[InputEdit],
+) {
+    // Sorted with touching ranges merged; a full parse has no old tree to diff against
+    let changed_ranges = old_tree.map_or_else(Vec::new, |old_tree| merge_ranges(new_tree.changed_ranges(old_tree).collect()));
+    let changed_bytes = match old_tree {
+        Some(_) => changed_ranges
+            .iter()
+            .map(|range| (range.start_byte, range.end_byte))
+            .chain(edited_byte_span(input_edits))
+            .reduce(|a, b| (a.0.min(b.0), a.1.max(b.1))),
+        None => Some((0, input_len)),
+    };
+
+    *tree_slot = Some(new_tree);
+    *resource.last_changed_bytes.lock_recover() = changed_bytes;
+    *resource.last_changed_ranges.lock_recover() = changed_ranges;
+}
+
+/// Timeout and cancellation flag a parse is bounded by
+#[derive(Clone, Copy)]
+struct ParseLimits<'a> {
+    timeout_micros: u64,
+    cancellation_flag: Option<&'a AtomicUsize>,
+}
+
+/// Parse input honoring a timeout and cancellation flag
+/// Returns the failure reason ("timeout", "cancelled" or "parse_error") when no tree is produced
+fn parse_with_limits(
+    limits: ParseLimits,
+    parser: &mut Parser,
+    input: &[u8],
+    old_tree: Option<&Tree>,
+) -> Result<Tree, &'static str> {
+    parse_chunks_with_limits(limits, parser, &mut |byte_offset, _| input.get(byte_offset..).unwrap_or_default(), old_tree)
+}
+
+/// Like parse_with_limits, but reads input through a tree-sitter chunk callback
+/// The callback returns the bytes starting at the requested offset; an empty chunk ends the input
+fn parse_chunks_with_limits<T, F>(
+    limits: ParseLimits,
+    parser: &mut Parser,
+    read: &mut F,
+    old_tree: Option<&Tree>,
+) -> Result<Tree, &'static str>
+where
+    T: AsRef<[u8]>,
+    F: FnMut(usize, Point) -> T,
+{
+    let timeout_micros = limits.timeout_micros;
+    let deadline = (timeout_micros > 0).then(|| Instant::now() + Duration::from_micros(timeout_micros));
+    let mut timed_out = false;
+    let mut cancelled = false;
+
+    // A cancel_parse that lands before the parse starts still cancels it; the flag is only
+    // cleared once a parse has consumed it, so it can't leak into the next one
+    let cancellation_flag = limits.cancellation_flag;
+    if cancellation_flag.is_some_and(|flag| flag.swap(0, Ordering::Relaxed) != 0) {
+        return Err("cancelled");
+    }
+
+    // Returning true from the progress callback halts the parse
+    // (replaces tree-sitter's deprecated set_timeout_micros/set_cancellation_flag)
+    let mut progress = |_: &ParseState| {
+        cancelled = cancellation_flag.is_some_and(|flag| flag.load(Ordering::Relaxed) != 0);
+        timed_out = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        cancelled || timed_out
+    };
+
+    let tree = parser.parse_with_options(
+        read,
+        old_tree,
+        Some(ParseOptions::new().progress_callback(&mut progress)),
+    );
+    if let Some(flag) = cancellation_flag {
+        flag.store(0, Ordering::Relaxed);
+    }
+
+    if tree.is_none() {
+        // A halted parse would otherwise resume on the next call with different input
+        parser.reset();
+    }
+
+    match tree {
+        Some(tree) => Ok(tree),
+        None if cancelled => Err("cancelled"),
+        None if timed_out => Err("timeout"),
+        None => Err("parse_error"),
+    }
+}
+
+/// Fetch the subtree for a byte range, converted at most max_depth levels deep
+/// Falls back to the smallest containing node and flags "range_adjusted"
+#[rustler::nif]
+fn get_subtree<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    start_byte: usize,
+    end_byte: usize,
+    max_depth: usize,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        if start_byte > end_byte || end_byte > input.len() {
+            return Ok((atoms::error(), error_map(env, "out_of_range")));
+        }
+
+        match tree.root_node().named_descendant_for_byte_range(start_byte, end_byte) {
+            Some(node) => {
+                let options = ConvertOptions { max_depth: Some(max_depth), ..resource.convert_options };
+                let mut subtree = convert_node_to_map_with(&node, input, env, &options);
+                if node.start_byte() != start_byte || node.end_byte() != end_byte {
+                    subtree.insert("range_adjusted".to_string(), true.encode(env));
+                }
+                Ok((atoms::ok(), subtree))
+            }
+            None => Ok((atoms::error(), error_map(env, "out_of_range"))),
+        }
+    })
+}
+
+/// Every node whose kind equals `kind`, in document order, as full AST maps
+/// Nested matches are included; max_results (nil for no limit) stops the walk early
+#[rustler::nif]
+fn find_nodes_of_kind<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    kind: String,
+    max_results: Option<usize>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let limit = max_results.unwrap_or(usize::MAX);
+        let mut nodes = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            if nodes.len() >= limit {
+                return false;
+            }
+            if node.kind() == kind {
+                nodes.push(convert_node_to_map_with(&node, input, env, &resource.convert_options));
+            }
+            nodes.len() < limit
+        });
+
+        Ok((atoms::ok(), nodes.encode(env)))
+    })
+}
+
+/// Collect every error and missing node in the current tree
+/// Missing nodes report the expected token kind as their "type"
+#[rustler::nif]
+fn get_errors<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, _| {
+        let mut errors = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            if node.is_error() || node.is_missing() {
+                let mut map = position_map(&node, env);
+                map.insert("type".to_string(), node.kind().encode(env));
+                map.insert("is_missing".to_string(), node.is_missing().encode(env));
+                errors.push(map);
+            }
+            // Only subtrees flagged with has_error can contain further errors
+            node.has_error()
+        });
+
+        Ok((atoms::ok(), errors.encode(env)))
+    })
+}
+
+/// Earliest error or missing node in document order, in the get_errors format, or nil
+/// Follows the first has_error child at each level instead of walking the whole tree
+#[rustler::nif]
+fn first_error_position<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, _| {
+        let mut node = tree.root_node();
+        if !node.has_error() {
+            return Ok((atoms::ok(), rustler::types::atom::nil().encode(env)));
+        }
+        while !node.is_error() && !node.is_missing() {
+            let mut cursor = node.walk();
+            let next = node.children(&mut cursor).find(|child| child.has_error());
+            match next {
+                Some(child) => node = child,
+                None => break,
+            }
+        }
+
+        let mut map = position_map(&node, env);
+        map.insert("type".to_string(), node.kind().encode(env));
+        map.insert("is_missing".to_string(), node.is_missing().encode(env));
+        Ok((atoms::ok(), map.encode(env)))
+    })
+}
+
+/// Describe the context around each error node to hint at what was expected
+/// Hints come from unmatched opening keywords/delimiters or missing tokens
+#[rustler::nif]
+fn get_error_context<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut contexts = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            if node.is_error() || node.is_missing() {
+                let mut first_leaf = node;
+                while let Some(child) = first_leaf.child(0) {
+                    first_leaf = child;
+                }
+
+                let (opener, expected) = if node.is_missing() {
+                    (None, Some(node.kind()))
+                } else {
+                    match unclosed_opener(&node) {
+                        Some((opener, closer)) => (Some(opener), Some(closer)),
+                        None => {
+                            let closer = node.parent().and_then(|parent| closing_delimiter(parent.kind()));
+                            (None, closer)
+                        }
+                    }
+                };
+
+                let hint = match (opener, expected) {
+                    (Some(opener), Some(expected)) => format!("expected `{}` to close `{}`", expected, opener),
+                    (None, Some(expected)) => format!("expected `{}`", expected),
+                    _ => "unexpected syntax".to_string(),
+                };
+
+                let mut map = position_map(&node, env);
+                map.insert("type".to_string(), node.kind().encode(env));
+                map.insert("is_missing".to_string(), node.is_missing().encode(env));
+                map.insert("first_leaf".to_string(), first_leaf.kind().encode(env));
+                map.insert("prev_sibling".to_string(), node.prev_sibling().map(|n| n.kind()).encode(env));
+                map.insert("next_sibling".to_string(), node.next_sibling().map(|n| n.kind()).encode(env));
+                map.insert("parent".to_string(), node.parent().map(|n| n.kind()).encode(env));
+                let snippet = input.line(node.start_position().row).map(String::from_utf8_lossy);
+                map.insert("snippet".to_string(), snippet.as_deref().encode(env));
+                map.insert("opener".to_string(), opener.encode(env));
+                map.insert("expected".to_string(), expected.encode(env));
+                map.insert("hint".to_string(), hint.encode(env));
+                contexts.push(map);
+            }
+            node.has_error()
+        });
+
+        Ok((atoms::ok(), contexts.encode(env)))
+    })
+}
+
+/// Extract every command invocation with its name and argument words
+/// Names built from expansions are returned raw with "dynamic_name" set
+#[rustler::nif]
+fn get_commands<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut commands = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            if node.kind() == "command" {
+                commands.push(command_map(&node, input, env));
+            }
+            true
+        });
+
+        Ok((atoms::ok(), commands.encode(env)))
+    })
+}
+
+/// Lexical context at a cursor offset, for gating completions
+/// The innermost recognised enclosing node decides; anything else is :code
+#[rustler::nif]
+fn classify_offset<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    byte_offset: usize,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        if byte_offset > input.len() {
+            return Ok((atoms::error(), error_map(env, "out_of_range").encode(env)));
+        }
+
+        // The character before the cursor decides, so the end of a word still counts as that word
+        let innermost = tree.root_node().descendant_for_byte_range(byte_offset.saturating_sub(1), byte_offset);
+        let class = std::iter::successors(innermost, |node| node.parent())
+            .find_map(|node| match node.kind() {
+                "comment" => Some(atoms::comment()),
+                "heredoc_body" => Some(atoms::heredoc_body()),
+                "variable_name" | "special_variable_name" | "simple_expansion" | "expansion" => Some(atoms::variable()),
+                "command_name" => Some(atoms::command_name()),
+                "string" | "raw_string" | "ansi_c_string" | "translated_string" => Some(atoms::string()),
+                _ => None,
+            })
+            .unwrap_or_else(atoms::code);
+
+        Ok((atoms::ok(), class.encode(env)))
+    })
+}
+
+/// Whether any command in the tree is invoked by the given name
+/// Stops descending as soon as a match is found, without building command maps
+#[rustler::nif]
+fn contains_command<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    name: String,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut found = false;
+        walk_tree(tree.root_node(), |node| {
+            if found {
+                return false;
+            }
+            if node.kind() == "command" {
+                found = node
+                    .child_by_field_name("name")
+                    .is_some_and(|name_node| node_text(&name_node, input) == name);
+            }
+            !found
+        });
+
+        Ok((atoms::ok(), found.encode(env)))
+    })
+}
+
+/// Extract `alias name=value` definitions with their expansion text and later invocations
+/// "usages" lists commands named after the alias between its definition and any redefinition
+#[rustler::nif]
+fn get_aliases<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut definitions: Vec<(tree_sitter::Node, String, String)> = Vec::new();
+        let mut commands: Vec<(tree_sitter::Node, Cow<str>)> = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            if node.kind() != "command" {
+                return true;
+            }
+            let name = match node.child_by_field_name("name") {
+                Some(name_node) => node_text(&name_node, input),
+                None => return true,
+            };
+            if name != "alias" {
+                commands.push((node, name));
+                return true;
+            }
+
+            let mut cursor = node.walk();
+            for argument in node.children_by_field_name("argument", &mut cursor) {
+                // `ll='ls -l'` is a concatenation of `ll=` and a quoted string
+                let mut part_cursor = argument.walk();
+                let word: String = match argument.kind() {
+                    "concatenation" => argument
+                        .named_children(&mut part_cursor)
+                        .map(|part| unquoted_text(&part, input))
+                        .collect(),
+                    _ => unquoted_text(&argument, input).to_string(),
+                };
+                if let Some((alias, expansion)) = word.split_once('=') {
+                    definitions.push((argument, alias.to_string(), expansion.to_string()));
+                }
+            }
+            true
+        });
+
+        let aliases: Vec<HashMap<String, Term<'env>>> = definitions
+            .iter()
+            .enumerate()
+            .map(|(index, (argument, alias, expansion))| {
+                let redefined_at = definitions[index + 1..]
+                    .iter()
+                    .find(|(_, later, _)| later == alias)
+                    .map_or(usize::MAX, |(later, _, _)| later.start_byte());
+                let usages: Vec<HashMap<String, Term<'env>>> = commands
+                    .iter()
+                    .filter(|(command, name)| {
+                        name == alias && command.start_byte() > argument.end_byte() && command.start_byte() < redefined_at
+                    })
+                    .map(|(command, _)| position_map(command, env))
+                    .collect();
+
+                let mut map = position_map(argument, env);
+                map.insert("name".to_string(), alias.encode(env));
+                map.insert("expansion".to_string(), expansion.encode(env));
+                map.insert("usages".to_string(), usages.encode(env));
+                map
+            })
+            .collect();
+
+        Ok((atoms::ok(), aliases.encode(env)))
+    })
+}
+
+/// Extract `( ... )` subshells (:subshell) and `{ ...; }` command groups (:group) with their commands
+/// Function bodies are groups too and are marked with "function_body"; nested constructs are listed separately
+#[rustler::nif]
+fn get_subshells<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut blocks = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            let kind = match node.kind() {
+                "subshell" => atoms::subshell(),
+                "compound_statement" => atoms::group(),
+                _ => return true,
+            };
+
+            let mut commands = Vec::new();
+            walk_tree(node, |inner| {
+                if inner.kind() == "command" {
+                    commands.push(command_map(&inner, input, env));
+                }
+                true
+            });
+
+            let function_body = node.parent().is_some_and(|parent| parent.kind() == "function_definition");
+            let mut map = position_map(&node, env);
+            map.insert("type".to_string(), kind.encode(env));
+            map.insert("function_body".to_string(), function_body.encode(env));
+            map.insert("commands".to_string(), commands.encode(env));
+            map.insert("text".to_string(), node_text(&node, input).encode(env));
+            blocks.push(map);
+            true
+        });
+
+        Ok((atoms::ok(), blocks.encode(env)))
+    })
+}
+
+/// Extract every function definition with its body range and called commands
+/// "style" distinguishes `name()` (:posix) from `function name` (:function_keyword)
+#[rustler::nif]
+fn get_function_definitions<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut functions = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            if node.kind() == "function_definition" {
+                let name = node.child_by_field_name("name").map(|name| node_text(&name, input));
+                let style = match node.child(0) {
+                    Some(first) if first.kind() == "function" => atoms::function_keyword(),
+                    _ => atoms::posix(),
+                };
+
+                let mut map = position_map(&node, env);
+                map.insert("name".to_string(), name.as_deref().encode(env));
+                map.insert("style".to_string(), style.encode(env));
+
+                if let Some(body) = node.child_by_field_name("body") {
+                    let mut commands = Vec::new();
+                    walk_tree(body, |inner| {
+                        if inner.kind() == "command" {
+                            commands.push(command_map(&inner, input, env));
+                        }
+                        true
+                    });
+
+                    map.insert("body_type".to_string(), body.kind().encode(env));
+                    map.insert("body_start_byte".to_string(), body.start_byte().encode(env));
+                    map.insert("body_end_byte".to_string(), body.end_byte().encode(env));
+                    map.insert("commands".to_string(), commands.encode(env));
+                }
+
+                functions.push(map);
+            }
+            true
+        });
+
+        Ok((atoms::ok(), functions.encode(env)))
+    })
+}
+
+/// Extract every variable assignment with its value and export status
+/// "value_type" classifies the value as :word, :string, :command_substitution, :array, ...
+#[rustler::nif]
+fn get_variable_assignments<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut assignments = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            if node.kind() == "variable_assignment" {
+                let name = node.child_by_field_name("name").map(|name| node_text(&name, input));
+                let value = node.child_by_field_name("value");
+                let value_type = match value.map(|value| value.kind()) {
+                    None => atoms::empty(),
+                    Some("word") | Some("number") | Some("concatenation") => atoms::word(),
+                    Some("string") | Some("raw_string") | Some("ansi_c_string") | Some("translated_string") => atoms::string(),
+                    Some("command_substitution") => atoms::command_substitution(),
+                    Some("array") => atoms::array(),
+                    Some("simple_expansion") | Some("expansion") => atoms::expansion(),
+                    Some(_) => atoms::other(),
+                };
+
+                let mut map = position_map(&node, env);
+                map.insert("name".to_string(), name.as_deref().encode(env));
+                map.insert("value_text".to_string(), value.map(|value| node_text(&value, input)).as_deref().encode(env));
+                map.insert("value_type".to_string(), value_type.encode(env));
+                map.insert("is_exported".to_string(), is_exported_assignment(&node, input).encode(env));
+                assignments.push(map);
+            }
+            true
+        });
+
+        Ok((atoms::ok(), assignments.encode(env)))
+    })
+}
+
+/// Extract every variable reference ($FOO, ${FOO}, ${FOO:-default}, ...)
+/// "has_operator" marks parameter expansions that apply an operator
+#[rustler::nif]
+fn get_variable_references<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut references = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            if node.kind() == "simple_expansion" || node.kind() == "expansion" {
+                let has_operator = node.child_by_field_name("operator").is_some();
+
+                let mut map = position_map(&node, env);
+                map.insert("type".to_string(), node.kind().encode(env));
+                map.insert("name".to_string(), expansion_variable_name(&node, input).as_deref().encode(env));
+                map.insert("text".to_string(), node_text(&node, input).encode(env));
+                map.insert("has_operator".to_string(), has_operator.encode(env));
+                references.push(map);
+            }
+            true
+        });
+
+        Ok((atoms::ok(), references.encode(env)))
+    })
+}
+
+/// Extract every command substitution in both $(...) and backtick forms
+/// Nested substitutions report how many substitutions enclose them as "depth"
+#[rustler::nif]
+fn get_command_substitutions<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut substitutions = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            if node.kind() == "command_substitution" {
+                let style = match node.child(0) {
+                    Some(open) if open.kind() == "`" => atoms::backtick(),
+                    _ => atoms::dollar_paren(),
+                };
+
+                let mut depth = 0;
+                let mut ancestor = node.parent();
+                while let Some(parent) = ancestor {
+                    if parent.kind() == "command_substitution" {
+                        depth += 1;
+                    }
+                    ancestor = parent.parent();
+                }
+
+                let mut map = position_map(&node, env);
+                map.insert("text".to_string(), node_text(&node, input).encode(env));
+                map.insert("command_text".to_string(), delimited_inner_text(&node, input).encode(env));
+                map.insert("style".to_string(), style.encode(env));
+                map.insert("depth".to_string(), depth.encode(env));
+                substitutions.push(map);
+            }
+            true
+        });
+
+        Ok((atoms::ok(), substitutions.encode(env)))
+    })
+}
+
+/// Extract every process substitution, `<(...)` as :input and `>(...)` as :output
+/// "redirect" is true when the substitution is a redirection target, as in `> >(tee log)`
+#[rustler::nif]
+fn get_process_substitutions<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut substitutions = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            if node.kind() == "process_substitution" {
+                let direction = match node.child(0) {
+                    Some(open) if open.kind() == ">(" => atoms::output(),
+                    _ => atoms::input(),
+                };
+                let redirect = node.parent().is_some_and(|parent| parent.kind().ends_with("_redirect"));
+
+                let mut map = position_map(&node, env);
+                map.insert("text".to_string(), node_text(&node, input).encode(env));
+                map.insert("command_text".to_string(), delimited_inner_text(&node, input).encode(env));
+                map.insert("direction".to_string(), direction.encode(env));
+                map.insert("redirect".to_string(), redirect.encode(env));
+                substitutions.push(map);
+            }
+            true
+        });
+
+        Ok((atoms::ok(), substitutions.encode(env)))
+    })
+}
+
+/// Extract every heredoc with its delimiter, quoting, and body
+/// Unterminated heredocs have a missing heredoc_end and report "is_terminated: false"
+#[rustler::nif]
+fn get_heredocs<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut heredocs = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            if node.kind() == "heredoc_redirect" {
+                let mut start = None;
+                let mut body = None;
+                let mut end = None;
+                let mut strip_tabs = false;
+
+                let mut cursor = node.walk();
+                for child in node.children(&mut cursor) {
+                    match child.kind() {
+                        "heredoc_start" => start = Some(child),
+                        "heredoc_body" => body = Some(child),
+                        "heredoc_end" => end = Some(child),
+                        "<<-" => strip_tabs = true,
+                        _ => {}
+                    }
+                }
+
+                // Any quoting of the delimiter (e.g. 'EOF', "EOF", \EOF) disables expansion in the body
+                let raw_delimiter = start.map(|start| node_text(&start, input)).unwrap_or_default();
+                let is_quoted = raw_delimiter.contains(['\'', '"', '\\']);
+                let delimiter: String = raw_delimiter.chars().filter(|c| !matches!(c, '\'' | '"' | '\\')).collect();
+                let is_terminated = end.is_some_and(|end| !end.is_missing());
+
+                let mut map = position_map(&node, env);
+                map.insert("delimiter".to_string(), delimiter.encode(env));
+                map.insert("is_quoted".to_string(), is_quoted.encode(env));
+                map.insert("strip_tabs".to_string(), strip_tabs.encode(env));
+                map.insert("is_terminated".to_string(), is_terminated.encode(env));
+                map.insert("body".to_string(), body.map(|body| node_text(&body, input)).unwrap_or_default().encode(env));
+                map.insert("body_start_byte".to_string(), body.map(|body| body.start_byte()).encode(env));
+                map.insert("body_end_byte".to_string(), body.map(|body| body.end_byte()).encode(env));
+                heredocs.push(map);
+            }
+            true
+        });
+
+        Ok((atoms::ok(), heredocs.encode(env)))
+    })
+}
+
+/// Extract every redirection with its operator, target, and attached command
+/// The operator includes any explicit file descriptor (e.g. "2>")
+#[rustler::nif]
+fn get_redirections<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut redirections = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            if matches!(node.kind(), "file_redirect" | "heredoc_redirect" | "herestring_redirect") {
+                let descriptor = node.child_by_field_name("descriptor").map(|fd| node_text(&fd, input));
+
+                let mut operator = None;
+                let mut target = node.child_by_field_name("destination");
+                let mut cursor = node.walk();
+                for child in node.children(&mut cursor) {
+                    if !child.is_named() && operator.is_none() {
+                        operator = Some(child.kind());
+                    } else if target.is_none() && child.is_named() && child.kind() != "file_descriptor" {
+                        // Heredocs and herestrings have no destination field; use the delimiter/word
+                        target = Some(child);
+                    }
+                }
+                let operator = format!("{}{}", descriptor.as_deref().unwrap_or(""), operator.unwrap_or(""));
+
+                // The command is the body of the enclosing redirected_statement
+                let mut command = None;
+                let mut ancestor = node.parent();
+                while let Some(parent) = ancestor {
+                    if parent.kind() == "redirected_statement" {
+                        command = parent.child_by_field_name("body");
+                        break;
+                    }
+                    ancestor = parent.parent();
+                }
+
+                let mut map = position_map(&node, env);
+                map.insert("type".to_string(), node.kind().encode(env));
+                map.insert("operator".to_string(), operator.encode(env));
+                map.insert("descriptor".to_string(), descriptor.as_deref().encode(env));
+                map.insert("target".to_string(), target.map(|target| node_text(&target, input)).as_deref().encode(env));
+                map.insert("command".to_string(), command.map(|command| node_text(&command, input)).as_deref().encode(env));
+                map.insert("command_start_byte".to_string(), command.map(|command| command.start_byte()).encode(env));
+                map.insert("command_end_byte".to_string(), command.map(|command| command.end_byte()).encode(env));
+                redirections.push(map);
+            }
+            true
+        });
+
+        Ok((atoms::ok(), redirections.encode(env)))
+    })
+}
+
+/// Extract every pipeline as an ordered list of its stages
+/// Pipelines split by a mid-pipeline redirect are flattened back into one stage list
+#[rustler::nif]
+fn get_pipelines<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut pipelines = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            if node.kind() == "pipeline" && !is_nested_pipeline(&node) {
+                let mut stages = Vec::new();
+                let mut uses_stderr_pipe = false;
+                collect_pipeline_stages(node, &mut stages, &mut uses_stderr_pipe);
+
+                let stage_maps: Vec<HashMap<String, Term<'env>>> = stages
+                    .iter()
+                    .map(|stage| {
+                        let mut map = match stage_command(stage) {
+                            Some(command) => command_map(&command, input, env),
+                            None => position_map(stage, env),
+                        };
+                        map.insert("type".to_string(), stage.kind().encode(env));
+                        map.insert("text".to_string(), node_text(stage, input).encode(env));
+                        map
+                    })
+                    .collect();
+
+                let mut map = position_map(&node, env);
+                map.insert("commands".to_string(), stage_maps.encode(env));
+                map.insert("uses_stderr_pipe".to_string(), uses_stderr_pipe.encode(env));
+                pipelines.push(map);
+            }
+            true
+        });
+
+        Ok((atoms::ok(), pipelines.encode(env)))
+    })
+}
+
+/// Extract every comment node in document order
+/// Comments are extras, so they are collected wherever tree-sitter attached them
+#[rustler::nif]
+fn get_comments<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut comments = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            if node.kind() == "comment" {
+                let mut map = position_map(&node, env);
+                map.insert("text".to_string(), node_text(&node, input).encode(env));
+                comments.push(map);
+            }
+            true
+        });
+
+        Ok((atoms::ok(), comments.encode(env)))
+    })
+}
+
+/// Interpreter named by a leading `#!` comment, split into {path, args}
+/// Returns {:ok, nil} when the script does not start with a shebang
+#[rustler::nif]
+fn get_shebang<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let shebang = tree
+            .root_node()
+            .child(0)
+            .filter(|node| node.kind() == "comment" && node.start_byte() == 0)
+            .and_then(|node| {
+                let text = node_text(&node, input);
+                let mut words = text.strip_prefix("#!")?.split_whitespace();
+                let path = words.next()?.to_string();
+                Some((path, words.map(str::to_string).collect::<Vec<_>>()))
+            });
+
+        match shebang {
+            Some(shebang) => Ok((atoms::ok(), shebang.encode(env))),
+            None => Ok((atoms::ok(), rustler::types::atom::nil().encode(env))),
+        }
+    })
+}
+
+/// Collect every leaf node (keywords and operators included) in source order
+/// Each token carries its kind as "type", whether it is named, its text and position
+#[rustler::nif]
+fn get_tokens<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut tokens = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            if node.child_count() == 0 {
+                let mut map = position_map(&node, env);
+                map.insert("type".to_string(), node.kind().encode(env));
+                map.insert("named".to_string(), node.is_named().encode(env));
+                map.insert("text".to_string(), node_text(&node, input).encode(env));
+                if node.is_missing() {
+                    map.insert("is_missing".to_string(), true.encode(env));
+                }
+                tokens.push(map);
+            }
+            true
+        });
+
+        Ok((atoms::ok(), tokens.encode(env)))
+    })
+}
+
+/// Get the current tree as tree-sitter's canonical S-expression
+#[rustler::nif]
+fn to_sexp<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, _| Ok((atoms::ok(), tree.root_node().to_sexp().encode(env))))
+}
+
+/// Get the current tree as a Graphviz digraph of its named nodes
+/// Nodes are labeled with kind and a text snippet; field edges carry the field name
+#[rustler::nif]
+fn to_dot<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut dot = String::from("digraph ast {\n  node [shape=box, fontname=\"monospace\"];\n");
+        let mut next_id = 0;
+        write_dot_node(&tree.root_node(), input, &mut dot, &mut next_id);
+        dot.push_str("}\n");
+
+        Ok((atoms::ok(), dot.encode(env)))
+    })
+}
+
+/// Stateless S-expression parse (parallels parse_bash)
+#[rustler::nif]
+fn to_sexp_string(content: String) -> NifResult<(Atom, String)> {
+    let mut parser = new_bash_parser().map_err(|err| Error::Term(Box::new(err)))?;
+
+    match parser.parse(&content, None) {
+        Some(tree) => Ok((atoms::ok(), tree.root_node().to_sexp())),
+        None => Err(Error::Atom("failed_to_parse")),
+    }
+}
+
+/// Get the current AST serialized as a single JSON binary
+/// Field names match convert_node_to_map; avoids building thousands of map terms
+#[rustler::nif]
+fn get_current_ast_json<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let json = convert_node_to_json(&tree.root_node(), input).to_string();
+        Ok((atoms::ok(), json.encode(env)))
+    })
+}
+
+/// Options controlling how nodes are converted to Elixir maps
+#[derive(Clone, Copy)]
+struct ConvertOptions {
+    /// Stop recursing this many levels below the converted node
+    max_depth: Option<usize>,
+    /// Report start_col/end_col in UTF-16 code units instead of bytes (for LSP)
+    utf16_columns: bool,
+    /// Emit is_missing/is_extra/is_error/has_error
+    include_error_flags: bool,
+    /// Emit named children that have no field name under "children"
+    include_children: bool,
+    /// Emit each node's source text (large for big nodes)
+    include_text: bool,
+    /// Replace text with nil (plus text_truncated and the byte range) for longer nodes
+    max_text_bytes: Option<usize>,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        ConvertOptions {
+            max_depth: None,
+            utf16_columns: false,
+            include_error_flags: true,
+            include_children: true,
+            include_text: true,
+            max_text_bytes: None,
+        }
+    }
+}
+
+/// Convert a byte offset in accumulated input to a {row, col} point
+#[rustler::nif(name = "byte_to_point")]
+fn byte_to_point_nif<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    byte_offset: usize,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    let input = resource.accumulated_input.lock_recover();
+    if byte_offset > input.len() || !input.is_char_boundary(byte_offset) {
+        return Ok((atoms::error(), error_map(env, "out_of_range").encode(env)));
+    }
+
+    let point = input.byte_to_point(byte_offset);
+    Ok((atoms::ok(), (point.row, point.column).encode(env)))
+}
+
+/// Convert a row/col point in accumulated input to a byte offset
+#[rustler::nif(name = "point_to_byte")]
+fn point_to_byte_nif<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    row: usize,
+    col: usize,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    let input = resource.accumulated_input.lock_recover();
+    match input.point_to_byte(row, col) {
+        Some(byte_offset) => Ok((atoms::ok(), byte_offset.encode(env))),
+        None => Ok((atoms::error(), error_map(env, "out_of_range").encode(env))),
+    }
+}
+
+/// Build a hierarchical document outline (LSP documentSymbol style)
+/// Functions and control blocks nest their contained symbols under "children"
+#[rustler::nif]
+fn get_outline<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let outline = collect_outline(&tree.root_node(), input, env, true);
+
+        Ok((atoms::ok(), outline.encode(env)))
+    })
+}
+
+/// Produce a flat list of highlight spans using a tree-sitter highlights query
+/// Pass :default to use tree-sitter-bash's bundled highlights.scm
+#[rustler::nif(name = "highlight")]
+fn highlight_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ParserResource>,
+    highlight_query: Term<'a>,
+) -> NifResult<(Atom, Term<'a>)> {
+    use rustler::Encoder;
+
+    let query_source = highlight_query_source(highlight_query)?;
+    let input = resource.accumulated_input.lock_recover();
+
+    match highlight_spans(&input, query_source.as_deref()) {
+        Ok(spans) => Ok((atoms::ok(), encode_highlight_spans(&spans, 0, env).encode(env))),
+        Err(error) => Ok((atoms::error(), highlight_error_map(error, env).encode(env))),
+    }
+}
+
+/// Re-highlight only the region touched by the last parse, using the bundled highlights query
+/// The region is widened to whole top-level statements so multi-line tokens stay intact
+#[rustler::nif]
+fn highlight_changed<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let (window_start, window_end) = match *resource.last_changed_bytes.lock_recover() {
+            Some((start, end)) => statement_window(tree, start, end),
+            None => (0, 0),
+        };
+
+        let spans = if window_start < window_end {
+            match highlight_spans(&input[window_start..window_end], None) {
+                Ok(spans) => spans,
+                Err(error) => return Ok((atoms::error(), highlight_error_map(error, env).encode(env))),
+            }
+        } else {
+            Vec::new()
+        };
+
+        let mut result = HashMap::new();
+        result.insert("start_byte".to_string(), window_start.encode(env));
+        result.insert("end_byte".to_string(), window_end.encode(env));
+        result.insert("spans".to_string(), encode_highlight_spans(&spans, window_start, env).encode(env));
+        Ok((atoms::ok(), result.encode(env)))
+    })
+}
+
+/// Export the resource's input and settings as a JSON binary for import_state
+/// The tree itself is not serialized; import_state re-derives it by parsing the input
+#[rustler::nif]
+fn export_state(resource: ResourceArc<ParserResource>) -> (Atom, String) {
+    let input = resource.accumulated_input.lock_recover();
+    // JSON strings cannot hold bytes that are not valid UTF-8, so such input goes as a byte list
+    let text = std::str::from_utf8(&input).ok();
+    let state = serde_json::json!({
+        "version": 1,
+        "input": text,
+        "input_bytes": text.is_none().then(|| input.to_vec()),
+        "max_buffer_size": resource.max_buffer_size.load(Ordering::Relaxed),
+        "window_bytes": resource.window_bytes,
+        "timeout_micros": resource.timeout_micros.load(Ordering::Relaxed),
+        "cancellable": resource.cancellation_flag.is_some(),
+        "include_error_flags": resource.convert_options.include_error_flags,
+        "include_children": resource.convert_options.include_children,
+        "include_text": resource.convert_options.include_text,
+        "auto_flush": resource.auto_flush,
+        "statement_container": resource.statement_container,
+        "include_timing": resource.include_timing,
+    });
+
+    (atoms::ok(), state.to_string())
+}
+
+/// Rebuild a parser resource from export_state output with a single full parse
+/// Returns {:error, %{"reason" => "invalid_state"}} if the binary is not a recognised export
+#[rustler::nif]
+fn import_state<'env>(env: Env<'env>, state: String) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    let Some(state) = decode_exported_state(&state) else {
+        return Ok((atoms::error(), error_map(env, "invalid_state").encode(env)));
+    };
+
+    if state.input.len() > state.max_buffer_size {
+        let mut map = error_map(env, "buffer_overflow");
+        map.insert("current_size".to_string(), state.input.len().encode(env));
+        map.insert("max_size".to_string(), state.max_buffer_size.encode(env));
+        return Ok((atoms::error(), map.encode(env)));
+    }
+
+    let resource = match ParserResource::new(state.max_buffer_size) {
+        Ok(resource) => ParserResource {
+            window_bytes: state.window_bytes,
+            timeout_micros: AtomicU64::new(state.timeout_micros),
+            cancellation_flag: state.cancellable.then(|| Arc::new(AtomicUsize::new(0))),
+            convert_options: state.convert_options,
+            auto_flush: state.auto_flush,
+            statement_container: state.statement_container,
+            include_timing: state.include_timing,
+            ..resource
+        },
+        Err(msg) => return Err(Error::Term(Box::new(msg))),
+    };
+
+    if !state.input.is_empty() {
+        let tree = {
+            let mut parser = resource.parser.lock_recover();
+            parse_with_limits(resource.limits(), &mut parser, &state.input, None)
+        };
+        match tree {
+            Ok(tree) => *resource.old_tree.lock_recover() = Some(tree),
+            Err(reason) => return Ok((atoms::error(), error_map(env, reason).encode(env))),
+        }
+        *resource.last_changed_bytes.lock_recover() = Some((0, state.input.len()));
+        resource.last_changed_ranges.lock_recover().clear();
+        *resource.accumulated_input.lock_recover() = InputBuffer::from(state.input);
+    }
+
+    Ok((atoms::ok(), ResourceArc::new(resource).encode(env)))
+}
+
+/// Fork a parser resource: copies accumulated input, tree and settings onto a fresh Parser
+/// Edits applied to the clone never affect the original
+#[rustler::nif]
+fn clone_parser(resource: ResourceArc<ParserResource>) -> NifResult<(Atom, ResourceArc<ParserResource>)> {
+    let parser = match new_bash_parser() {
+        Ok(parser) => parser,
+        Err(msg) => return Err(Error::Term(Box::new(msg))),
+    };
+
+    let clone = ParserResource {
+        parser: Mutex::new(parser),
+        old_tree: Mutex::new(resource.old_tree.lock_recover().clone()),
+        accumulated_input: Mutex::new(resource.accumulated_input.lock_recover().clone()),
+        max_buffer_size: AtomicUsize::new(resource.max_buffer_size.load(Ordering::Relaxed)),
+        window_bytes: resource.window_bytes,
+        timeout_micros: AtomicU64::new(resource.timeout_micros.load(Ordering::Relaxed)),
+        // The clone gets its own flag so cancelling one never halts the other
+        cancellation_flag: resource.cancellation_flag.as_ref().map(|_| Arc::new(AtomicUsize::new(0))),
+        last_changed_bytes: Mutex::new(*resource.last_changed_bytes.lock_recover()),
+        last_changed_ranges: Mutex::new(resource.last_changed_ranges.lock_recover().clone()),
+        convert_options: resource.convert_options,
+        auto_flush: resource.auto_flush,
+        statement_container: resource.statement_container,
+        include_timing: resource.include_timing,
+    };
+
+    Ok((atoms::ok(), ResourceArc::new(clone)))
+}
+
+/// Get cheap complexity metrics for the current tree without serializing it
+/// node_count includes the root; error_count counts ERROR and MISSING nodes
+#[rustler::nif]
+fn get_tree_stats<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, _| {
+        let stats = tree_stats(tree.root_node());
+
+        let mut result = HashMap::new();
+        result.insert("node_count".to_string(), stats.node_count.encode(env));
+        result.insert("named_node_count".to_string(), stats.named_node_count.encode(env));
+        result.insert("max_depth".to_string(), stats.max_depth.encode(env));
+        result.insert("error_count".to_string(), stats.error_count.encode(env));
+        result.insert("byte_size".to_string(), tree.root_node().end_byte().encode(env));
+        Ok((atoms::ok(), result.encode(env)))
+    })
+}
+
+/// Count of named nodes per kind across the whole tree, in one cursor walk
+/// Anonymous tokens (keywords, punctuation) are left out
+#[rustler::nif]
+fn kind_histogram<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, _| {
+        let mut counts: HashMap<&'static str, usize> = HashMap::new();
+        walk_tree(tree.root_node(), |node| {
+            if node.is_named() {
+                *counts.entry(node.kind()).or_default() += 1;
+            }
+            true
+        });
+
+        Ok((atoms::ok(), counts.encode(env)))
+    })
+}
+
+/// Flag commands that match a built-in or caller-supplied risk rule
+/// extra_rules are {rule_atom, command_name, arg_glob} tuples; an empty glob matches any arguments
+#[rustler::nif]
+fn find_dangerous_commands<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    extra_rules: Vec<(Atom, String, String)>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut findings = Vec::new();
+        let mut report = |node: &tree_sitter::Node, rule: Atom, severity: Atom| {
+            let mut map = position_map(node, env);
+            map.insert("rule".to_string(), rule.encode(env));
+            map.insert("severity".to_string(), severity.encode(env));
+            map.insert("text".to_string(), node_text(node, input).encode(env));
+            findings.push(map);
+        };
+
+        walk_tree(tree.root_node(), |node| {
+            match node.kind() {
+                "command" => {
+                    if let Some((name, args)) = effective_command(&node, input) {
+                        for (rule, severity) in builtin_command_risks(&name, &args) {
+                            report(&node, rule, severity);
+                        }
+                        for (rule, command_name, arg_pattern) in &extra_rules {
+                            if name == **command_name
+                                && (arg_pattern.is_empty() || args.iter().any(|arg| glob_match(arg_pattern, arg)))
+                            {
+                                report(&node, *rule, atoms::high());
+                            }
+                        }
+                    }
+                }
+                "file_redirect" => {
+                    let writes = node.children(&mut node.walk()).any(|child| matches!(child.kind(), ">" | ">>" | ">|" | "&>" | "&>>"));
+                    let target = node.child_by_field_name("destination").map(|target| unquoted_text(&target, input));
+                    if writes && target.is_some_and(|target| is_block_device(&target)) {
+                        let statement = node.parent().filter(|parent| parent.kind() == "redirected_statement");
+                        report(&statement.unwrap_or(node), atoms::device_write(), atoms::critical());
+                    }
+                }
+                "pipeline" if !is_nested_pipeline(&node) => {
+                    let mut stages = Vec::new();
+                    let mut uses_stderr_pipe = false;
+                    collect_pipeline_stages(node, &mut stages, &mut uses_stderr_pipe);
+
+                    let names: Vec<Cow<str>> = stages
+                        .iter()
+                        .filter_map(|stage| stage_command(stage))
+                        .filter_map(|command| effective_command(&command, input).map(|(name, _)| name))
+                        .collect();
+                    let downloads = names.iter().position(|name| matches!(&**name, "curl" | "wget"));
+                    if downloads.is_some_and(|first| names[first + 1..].iter().any(|name| is_shell(name))) {
+                        report(&node, atoms::curl_pipe_shell(), atoms::critical());
+                    }
+                }
+                _ => {}
+            }
+            true
+        });
+
+        Ok((atoms::ok(), findings.encode(env)))
+    })
+}
+
+/// Locate sudo, doas, su and pkexec invocations and the command each one escalates
+/// "command" is the escalated word list (empty for an interactive shell); ancestry flags give the context
+#[rustler::nif]
+fn find_privilege_escalation<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut findings = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            if node.kind() != "command" {
+                return true;
+            }
+            let name = match node.child_by_field_name("name") {
+                Some(name_node) => node_text(&name_node, input),
+                None => return true,
+            };
+            let tool = name.rsplit('/').next().unwrap_or(&name);
+            if !matches!(tool, "sudo" | "doas" | "su" | "pkexec") {
+                return true;
+            }
+
+            let mut cursor = node.walk();
+            let args: Vec<Cow<str>> = node
+                .children_by_field_name("argument", &mut cursor)
+                .map(|arg| unquoted_text(&arg, input))
+                .collect();
+            let args: Vec<&str> = args.iter().map(|arg| &**arg).collect();
+            let (user, command) = escalated_command(tool, &args);
+
+            let mut in_pipeline = false;
+            let mut in_substitution = false;
+            let mut ancestor = node.parent();
+            while let Some(parent) = ancestor {
+                match parent.kind() {
+                    "pipeline" => in_pipeline = true,
+                    "command_substitution" | "process_substitution" => in_substitution = true,
+                    _ => {}
+                }
+                ancestor = parent.parent();
+            }
+
+            let mut map = position_map(&node, env);
+            map.insert("tool".to_string(), tool.encode(env));
+            map.insert("user".to_string(), user.encode(env));
+            map.insert("command".to_string(), command.encode(env));
+            map.insert("text".to_string(), node_text(&node, input).encode(env));
+            map.insert("in_pipeline".to_string(), in_pipeline.encode(env));
+            map.insert("in_substitution".to_string(), in_substitution.encode(env));
+            findings.push(map);
+            true
+        });
+
+        Ok((atoms::ok(), findings.encode(env)))
+    })
+}
+
+/// Find variable expansions used unquoted as command arguments (subject to word splitting)
+/// `[[ ]]` operands are skipped since bash does not split them; `[ ]` operands are reported
+#[rustler::nif]
+fn find_unquoted_expansions<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut expansions = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            let (command_name, words) = match split_words(&node, input) {
+                Some(split) => split,
+                None => return true,
+            };
+
+            for word in words {
+                for part in word_parts(&word).iter().filter(|part| matches!(part.kind(), "simple_expansion" | "expansion")) {
+                    let mut map = position_map(part, env);
+                    map.insert("type".to_string(), part.kind().encode(env));
+                    map.insert("text".to_string(), node_text(part, input).encode(env));
+                    map.insert("variable".to_string(), expansion_variable_name(part, input).as_deref().encode(env));
+                    map.insert("command".to_string(), command_name.as_deref().encode(env));
+                    expansions.push(map);
+                }
+            }
+            true
+        });
+
+        Ok((atoms::ok(), expansions.encode(env)))
+    })
+}
+
+/// Quoting of every word in command name or argument position, with concatenations split into parts
+/// Unquoted expansions count as :unquoted words that contain expansions
+#[rustler::nif]
+fn classify_words<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut words = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            if node.kind() != "command" {
+                return true;
+            }
+
+            let name = node.child_by_field_name("name");
+            let command_name = name.map(|name| node_text(&name, input));
+            let mut cursor = node.walk();
+            let positioned = name
+                .and_then(|name| name.named_child(0))
+                .map(|word| (word, atoms::command_name()))
+                .into_iter()
+                .chain(node.children_by_field_name("argument", &mut cursor).map(|word| (word, atoms::argument())));
+
+            for (word, role) in positioned {
+                for part in word_parts(&word) {
+                    let quoting = match part.kind() {
+                        "word" | "number" | "simple_expansion" | "expansion" | "command_substitution"
+                        | "arithmetic_expansion" => atoms::unquoted(),
+                        "raw_string" => atoms::single(),
+                        "string" | "translated_string" => atoms::double(),
+                        "ansi_c_string" => atoms::ansi_c(),
+                        _ => continue,
+                    };
+                    let mut has_expansions = false;
+                    walk_tree(part, |inner| {
+                        has_expansions |= matches!(
+                            inner.kind(),
+                            "simple_expansion" | "expansion" | "command_substitution" | "arithmetic_expansion"
+                        );
+                        !has_expansions
+                    });
+
+                    let mut map = position_map(&part, env);
+                    map.insert("type".to_string(), part.kind().encode(env));
+                    map.insert("text".to_string(), node_text(&part, input).encode(env));
+                    map.insert("quoting".to_string(), quoting.encode(env));
+                    map.insert("has_expansions".to_string(), has_expansions.encode(env));
+                    map.insert("role".to_string(), role.encode(env));
+                    map.insert("command".to_string(), command_name.as_deref().encode(env));
+                    words.push(map);
+                }
+            }
+            true
+        });
+
+        Ok((atoms::ok(), words.encode(env)))
+    })
+}
+
+/// Run a small set of shellcheck rules (SC2086, SC2046, SC2164, SC2115) over the current tree
+/// Codes listed in `disabled` (e.g. "SC2164") are skipped; findings come back in document order
+#[rustler::nif]
+fn lint<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    disabled: Vec<String>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut findings = Vec::new();
+        let mut report = |node: &tree_sitter::Node, code: &str, severity: Atom, message: &str| {
+            if disabled.iter().any(|disabled_code| disabled_code == code) {
+                return;
+            }
+            let mut map = position_map(node, env);
+            map.insert("code".to_string(), code.encode(env));
+            map.insert("severity".to_string(), severity.encode(env));
+            map.insert("message".to_string(), message.encode(env));
+            map.insert("text".to_string(), node_text(node, input).encode(env));
+            findings.push(map);
+        };
+
+        walk_tree(tree.root_node(), |node| {
+            if let Some((_, words)) = split_words(&node, input) {
+                for part in words.iter().flat_map(word_parts) {
+                    match part.kind() {
+                        "simple_expansion" | "expansion" if !is_word_safe_expansion(&part, input) => report(
+                            &part,
+                            "SC2086",
+                            atoms::info(),
+                            "Double quote to prevent globbing and word splitting.",
+                        ),
+                        "command_substitution" => report(
+                            &part,
+                            "SC2046",
+                            atoms::warning(),
+                            "Quote this to prevent word splitting.",
+                        ),
+                        _ => {}
+                    }
+                }
+            }
+
+            if node.kind() == "command" {
+                let command = effective_command(&node, input);
+                match command.as_ref().map(|(name, args)| (&**name, args)) {
+                    Some(("cd", _)) if !exit_status_checked(&node) => report(
+                        &node,
+                        "SC2164",
+                        atoms::warning(),
+                        "Use 'cd ... || exit' or 'cd ... || return' in case cd fails.",
+                    ),
+                    Some(("rm", args)) if args.iter().any(|arg| is_recursive_flag(arg)) => {
+                        let mut cursor = node.walk();
+                        for argument in node.children_by_field_name("argument", &mut cursor) {
+                            if starts_with_unguarded_directory(&argument, input) {
+                                report(
+                                    &argument,
+                                    "SC2115",
+                                    atoms::warning(),
+                                    "Use \"${var:?}\" to ensure this never expands to / .",
+                                );
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            true
+        });
+
+        Ok((atoms::ok(), findings.encode(env)))
+    })
+}
+
+/// Extract test expressions with their operators, distinguishing `[ ]`, `[[ ]]` and the `test` builtin
+/// "style" is :single_bracket, :double_bracket or :test_builtin; "expression" excludes the brackets
+#[rustler::nif]
+fn get_test_commands<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut tests = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            let (style, expression, operators) = match node.kind() {
+                "test_command" => {
+                    let style = match node.child(0).map(|open| open.kind()) {
+                        Some("[[") => atoms::double_bracket(),
+                        _ => atoms::single_bracket(),
+                    };
+                    (style, delimited_inner_text(&node, input), test_operators(&node, input))
+                }
+                "command" if node.child_by_field_name("name").is_some_and(|name| node_text(&name, input) == "test") => {
+                    let mut cursor = node.walk();
+                    let args: Vec<_> = node.children_by_field_name("argument", &mut cursor).collect();
+                    let expression = match (args.first(), args.last()) {
+                        (Some(first), Some(last)) => String::from_utf8_lossy(&input[first.start_byte()..last.end_byte()]),
+                        _ => Cow::Borrowed(""),
+                    };
+                    // The builtin's operators are plain words, so they are recognized by spelling
+                    let operators = args
+                        .iter()
+                        .map(|arg| node_text(arg, input))
+                        .filter(|word| {
+                            matches!(&**word, "=" | "==" | "!=" | "<" | ">" | "!")
+                                || (word.len() > 1 && word.starts_with('-') && word[1..].chars().all(|c| c.is_ascii_alphabetic()))
+                        })
+                        .collect();
+                    (atoms::test_builtin(), expression, operators)
+                }
+                _ => return true,
+            };
+
+            let mut map = position_map(&node, env);
+            map.insert("style".to_string(), style.encode(env));
+            map.insert("expression".to_string(), expression.trim().encode(env));
+            map.insert("operators".to_string(), encode_texts(env, &operators));
+            map.insert("text".to_string(), node_text(&node, input).encode(env));
+            tests.push(map);
+            true
+        });
+
+        Ok((atoms::ok(), tests.encode(env)))
+    })
+}
+
+/// Extract arithmetic expansions (`$(( ))`, `$[ ]`) and arithmetic commands (`(( ))`)
+/// "variables" lists the distinct names referenced in the expression, in first-use order
+#[rustler::nif]
+fn get_arithmetic_expansions<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut expansions = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            let opener = node.child(0).map(|open| open.kind());
+            let style = match (node.kind(), opener) {
+                ("arithmetic_expansion", Some("((")) => atoms::command(),
+                ("arithmetic_expansion", _) => atoms::expansion(),
+                // `(( x++ ))` with a bare word operand parses as a test command
+                ("test_command", Some("((")) => atoms::command(),
+                _ => return true,
+            };
+
+            let mut map = position_map(&node, env);
+            map.insert("style".to_string(), style.encode(env));
+            map.insert("expression".to_string(), delimited_inner_text(&node, input).trim().encode(env));
+            map.insert("variables".to_string(), encode_texts(env, &arithmetic_variables(&node, input)));
+            map.insert("text".to_string(), node_text(&node, input).encode(env));
+            expansions.push(map);
+            true
+        });
+
+        Ok((atoms::ok(), expansions.encode(env)))
+    })
+}
+
+/// Enumerate the bash grammar's named node kinds and field names
+/// Kinds that the grammar aliases under several ids are listed once
+#[rustler::nif]
+fn language_info<'env>(env: Env<'env>) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    use rustler::Encoder;
+
+    let language: tree_sitter::Language = tree_sitter_bash::LANGUAGE.into();
+
+    let mut node_kinds: Vec<&str> = Vec::new();
+    for id in 0..language.node_kind_count() as u16 {
+        if !language.node_kind_is_named(id) {
+            continue;
+        }
+        if let Some(kind) = language.node_kind_for_id(id) {
+            if !node_kinds.contains(&kind) {
+                node_kinds.push(kind);
+            }
+        }
+    }
+
+    // Field ids start at 1; 0 means "no field"
+    let field_names: Vec<&str> = (1..=language.field_count() as u16)
+        .filter_map(|id| language.field_name_for_id(id))
+        .collect();
+
+    let mut result = HashMap::new();
+    result.insert("node_kind_count".to_string(), language.node_kind_count().encode(env));
+    result.insert("node_kinds".to_string(), node_kinds.encode(env));
+    result.insert("field_names".to_string(), field_names.encode(env));
+    Ok((atoms::ok(), result))
+}
+
+/// Report the grammar's ABI version and semantic version for compatibility checks
+/// grammar_version comes from the grammar's tree-sitter.json metadata and is nil if it was not generated
+#[rustler::nif]
+fn language_version<'env>(env: Env<'env>) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    use rustler::Encoder;
+
+    let language: tree_sitter::Language = tree_sitter_bash::LANGUAGE.into();
+    let grammar_version = language.metadata().map(|metadata| {
+        format!("{}.{}.{}", metadata.major_version, metadata.minor_version, metadata.patch_version)
+    });
+
+    let mut result = HashMap::new();
+    result.insert("abi_version".to_string(), language.abi_version().encode(env));
+    result.insert("grammar_version".to_string(), grammar_version.encode(env));
+    // ABI range the linked tree-sitter runtime accepts
+    result.insert("max_abi_version".to_string(), tree_sitter::LANGUAGE_VERSION.encode(env));
+    result.insert("min_abi_version".to_string(), tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION.encode(env));
+    Ok((atoms::ok(), result))
+}
+
+/// Check a query string against the bash grammar without running it
+/// Errors carry a kind atom (:syntax, :node_type, :field, :capture, :predicate, :structure, :language)
+#[rustler::nif]
+fn validate_query<'env>(env: Env<'env>, query_string: String) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    use rustler::Encoder;
+
+    match compile_query(&query_string) {
+        Ok(query) => {
+            let mut result = HashMap::new();
+            result.insert("pattern_count".to_string(), query.pattern_count().encode(env));
+            result.insert("capture_names".to_string(), query.capture_names().encode(env));
+            Ok((atoms::ok(), result))
+        }
+        Err(err) => Ok((atoms::error(), query_error_map(&err, env))),
+    }
+}
+
+/// Parse a fragment like parse_incremental but stream the results to pid
+/// Sends {:changed_node, map} per changed node, then {:parse_done, stats}; returns {:ok, stats}
+#[rustler::nif]
+fn parse_incremental_streaming<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    fragment: String,
+    pid: LocalPid,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    // Committed by the time it returns, so the receiver sees the new state when it handles the messages
+    let appended = append_and_reparse(env, &resource, fragment.as_bytes(), |input, new_tree, old_tree, _| {
+        let changed_nodes = changed_node_maps(new_tree, old_tree, input, env, &resource.convert_options, resource.statement_container);
+        let mut stats = HashMap::new();
+        stats.insert("changed_node_count".to_string(), changed_nodes.len().encode(env));
+        stats.insert("has_errors".to_string(), new_tree.root_node().has_error().encode(env));
+        stats.insert("byte_size".to_string(), input.len().encode(env));
+        (changed_nodes, stats)
+    });
+    let ((changed_nodes, mut stats), PreparedAppend { evicted_bytes, flushed, .. }, _) = match appended {
+        Ok(appended) => appended,
+        Err(map) => return Ok((atoms::error(), map.encode(env))),
+    };
+
+    if resource.window_bytes.is_some() {
+        stats.insert("evicted_bytes".to_string(), evicted_bytes.encode(env));
+    }
+    if flushed.is_some() {
+        stats.insert("flushed_bytes".to_string(), evicted_bytes.encode(env));
+    }
+
+    // Each message is copied to the receiver, so the NIF's own return term stays small
+    for node in flushed.into_iter().flatten() {
+        let _ = env.send(&pid, (atoms::flushed_node(), node));
+    }
+    for node in changed_nodes {
+        let _ = env.send(&pid, (atoms::changed_node(), node));
+    }
+    let _ = env.send(&pid, (atoms::parse_done(), stats.clone()));
+    Ok((atoms::ok(), stats.encode(env)))
+}
+
+/// Find if/case/loop/function/quote/heredoc constructs that are never closed
+/// Each entry has the construct kind, the opening token's range, and the expected closer
+#[rustler::nif]
+fn find_unterminated<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut unterminated = Vec::new();
+        let mut report = |construct: &str, opener: &tree_sitter::Node, expected_close: &str| {
+            let mut map = HashMap::new();
+            map.insert("construct".to_string(), construct.encode(env));
+            map.insert("open_range".to_string(), position_map(opener, env).encode(env));
+            map.insert("expected_close".to_string(), expected_close.encode(env));
+            unterminated.push(map);
+        };
+
+        walk_tree(tree.root_node(), |node| {
+            if node.is_missing() {
+                // Recovery inserted the closer itself, e.g. a zero-width `fi`
+                let construct = node.parent().map(|parent| match parent.kind() {
+                    "do_group" => parent.parent().unwrap_or(parent),
+                    _ => parent,
+                });
+                if let (Some(construct), true) = (construct, is_closing_token(node.kind())) {
+                    if let Some(opener) = construct.child(0) {
+                        report(construct.kind(), &opener, node.kind());
+                    }
+                }
+            } else if node.is_error() {
+                match unclosed_opener_node(&node) {
+                    Some((opener, closer)) => report(opened_construct(opener.kind()), &opener, closer),
+                    None if node.child_count() == 0 && node_text(&node, input).starts_with('\'') => {
+                        // An unmatched single quote swallows the rest of the input as one error token
+                        report("raw_string", &node, "'");
+                    }
+                    None => {}
+                }
+            } else if node.kind() == "heredoc_redirect" {
+                let mut cursor = node.walk();
+                let children: Vec<_> = node.children(&mut cursor).collect();
+                let start = children.iter().find(|child| child.kind() == "heredoc_start");
+                let end = children.iter().find(|child| child.kind() == "heredoc_end");
+                // A heredoc cut off by EOF ends with an empty heredoc_end
+                if let (Some(start), Some(end)) = (start, end) {
+                    if end.start_byte() == end.end_byte() {
+                        let text = node_text(start, input);
+                        let delimiter = text.trim_matches(|c| c == '\'' || c == '"' || c == '\\');
+                        report("heredoc_redirect", start, delimiter);
+                    }
+                }
+            }
+            true
+        });
+
+        Ok((atoms::ok(), unterminated.encode(env)))
+    })
+}
+
+/// {start_byte, end_byte, {start_row, start_col}, {end_row, end_col}} as passed from Elixir
+type IncludedRangeTuple = (usize, usize, (usize, usize), (usize, usize));
+
+/// Parse only the given regions of content as bash (e.g. fenced blocks in Markdown)
+/// ranges are {start_byte, end_byte, {start_row, start_col}, {end_row, end_col}}; positions stay document-relative
+#[rustler::nif]
+fn parse_ranges<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+    content: String,
+    ranges: Vec<IncludedRangeTuple>,
+) -> NifResult<(Atom, HashMap<String, Term<'env>>)> {
+    use rustler::Encoder;
+
+    let included: Vec<Range> = ranges
+        .iter()
+        .map(|&(start_byte, end_byte, (start_row, start_col), (end_row, end_col))| Range {
+            start_byte,
+            end_byte,
+            start_point: Point { row: start_row, column: start_col },
+            end_point: Point { row: end_row, column: end_col },
+        })
+        .collect();
+
+    if let Some(index) = included.iter().position(|range| range.end_byte > content.len()) {
+        let mut map = error_map(env, "invalid_ranges");
+        map.insert("index".to_string(), index.encode(env));
+        return Ok((atoms::error(), map));
+    }
+
+    let mut parser = resource.parser.lock_recover();
+    if let Err(err) = parser.set_included_ranges(&included) {
+        let mut map = error_map(env, "invalid_ranges");
+        map.insert("index".to_string(), err.0.encode(env));
+        return Ok((atoms::error(), map));
+    }
+
+    let tree = parse_with_limits(resource.limits(), &mut parser, content.as_bytes(), None);
+    // Later parses on this resource cover the whole buffer again
+    let _ = parser.set_included_ranges(&[]);
+
+    match tree {
+        Ok(tree) => {
+            let mut ast = convert_node_to_map_with(&tree.root_node(), content.as_bytes(), env, &resource.convert_options);
+            if tree.root_node().has_error() {
+                ast.insert("has_errors".to_string(), true.encode(env));
+            }
+            Ok((atoms::ok(), ast))
+        }
+        Err(reason) => Ok((atoms::error(), error_map(env, reason))),
+    }
+}
+
+/// Extract every case statement with its subject and branches
+/// Each branch lists its `|`-separated patterns and the byte range of its body
+#[rustler::nif]
+fn get_case_statements<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut statements = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            if node.kind() == "case_statement" {
+                let subject = node.child_by_field_name("value").map(|value| node_text(&value, input));
+
+                let mut cursor = node.walk();
+                let branches: Vec<HashMap<String, Term<'env>>> = node
+                    .named_children(&mut cursor)
+                    .filter(|child| child.kind() == "case_item")
+                    .map(|item| {
+                        let mut patterns = Vec::new();
+                        let mut body = Vec::new();
+                        let mut item_cursor = item.walk();
+                        if item_cursor.goto_first_child() {
+                            loop {
+                                let child = item_cursor.node();
+                                match item_cursor.field_name() {
+                                    Some("value") => patterns.push(node_text(&child, input)),
+                                    Some(_) => {}
+                                    None if child.is_named() && !child.is_extra() => body.push(child),
+                                    None => {}
+                                }
+                                if !item_cursor.goto_next_sibling() {
+                                    break;
+                                }
+                            }
+                        }
+
+                        // The last item's terminator is optional and is not always tagged as a field
+                        let mut term_cursor = item.walk();
+                        let termination = item
+                            .children(&mut term_cursor)
+                            .map(|child| child.kind())
+                            .find(|kind| matches!(*kind, ";;" | ";&" | ";;&"));
+
+                        let mut map = position_map(&item, env);
+                        map.insert("patterns".to_string(), encode_texts(env, &patterns));
+                        map.insert("body_start_byte".to_string(), body.first().map(|first| first.start_byte()).encode(env));
+                        map.insert("body_end_byte".to_string(), body.last().map(|last| last.end_byte()).encode(env));
+                        map.insert("termination".to_string(), termination.encode(env));
+                        map
+                    })
+                    .collect();
+
+                let mut map = position_map(&node, env);
+                map.insert("subject".to_string(), subject.as_deref().encode(env));
+                map.insert("branches".to_string(), branches.encode(env));
+                statements.push(map);
+            }
+            true
+        });
+
+        Ok((atoms::ok(), statements.encode(env)))
+    })
+}
+
+/// Extract for/while/until loops with their iterables or conditions
+/// for-loop items keep their node type so `for x in $(ls)` can be told apart from literal words
+#[rustler::nif]
+fn get_loops<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let mut loops = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            let mut map = match node.kind() {
+                "for_statement" => {
+                    let mut map = position_map(&node, env);
+                    let variable = node.child_by_field_name("variable").map(|variable| node_text(&variable, input));
+
+                    let mut cursor = node.walk();
+                    let items: Vec<HashMap<String, Term<'env>>> = node
+                        .children_by_field_name("value", &mut cursor)
+                        .map(|item| {
+                            let mut item_map = position_map(&item, env);
+                            item_map.insert("type".to_string(), item.kind().encode(env));
+                            item_map.insert("text".to_string(), node_text(&item, input).encode(env));
+                            item_map
+                        })
+                        .collect();
+
+                    // Without `in`, the loop iterates over the positional parameters
+                    let mut keyword_cursor = node.walk();
+                    let has_in = node.children(&mut keyword_cursor).any(|child| child.kind() == "in");
+
+                    map.insert("type".to_string(), atoms::for_loop().encode(env));
+                    map.insert("variable".to_string(), variable.as_deref().encode(env));
+                    map.insert("items".to_string(), items.encode(env));
+                    map.insert("implicit_args".to_string(), (!has_in).encode(env));
+                    map
+                }
+                "c_style_for_statement" => {
+                    let mut map = position_map(&node, env);
+                    map.insert("type".to_string(), atoms::for_loop().encode(env));
+                    map.insert("c_style".to_string(), true.encode(env));
+                    for field in ["initializer", "condition", "update"] {
+                        let text = node.child_by_field_name(field).map(|part| node_text(&part, input));
+                        map.insert(field.to_string(), text.as_deref().encode(env));
+                    }
+                    map
+                }
+                "while_statement" => {
+                    let mut map = position_map(&node, env);
+                    let keyword = match node.child(0).map(|keyword| keyword.kind()) {
+                        Some("until") => atoms::until_loop(),
+                        _ => atoms::while_loop(),
+                    };
+
+                    let mut cursor = node.walk();
+                    let condition: Vec<_> = node
+                        .children_by_field_name("condition", &mut cursor)
+                        .filter(|part| part.is_named())
+                        .collect();
+
+                    map.insert("type".to_string(), keyword.encode(env));
+                    map.insert("condition_start_byte".to_string(), condition.first().map(|first| first.start_byte()).encode(env));
+                    map.insert("condition_end_byte".to_string(), condition.last().map(|last| last.end_byte()).encode(env));
+                    map
+                }
+                _ => return true,
+            };
+
+            let body = node.child_by_field_name("body");
+            map.insert("body_start_byte".to_string(), body.map(|body| body.start_byte()).encode(env));
+            map.insert("body_end_byte".to_string(), body.map(|body| body.end_byte()).encode(env));
+            loops.push(map);
+            true
+        });
+
+        Ok((atoms::ok(), loops.encode(env)))
+    })
+}
+
+/// Re-emit the script with compound statements consistently indented (two spaces)
+/// Simple statements are kept verbatim; trees with syntax errors are not formatted, and neither is
+/// input that is not valid UTF-8, as a lossy copy would silently change the script
+#[rustler::nif]
+fn format<'env>(
+    env: Env<'env>,
+    resource: ResourceArc<ParserResource>,
+) -> NifResult<(Atom, Term<'env>)> {
+    use rustler::Encoder;
+
+    with_tree(env, &resource, |tree, input| {
+        let root = tree.root_node();
+        if root.has_error() {
+            return Ok((atoms::error(), error_map(env, "has_errors").encode(env)));
+        }
+        let Ok(source) = std::str::from_utf8(input) else {
+            return Ok((atoms::error(), error_map(env, "invalid_utf8").encode(env)));
+        };
+
+        Ok((atoms::ok(), format_tree(tree, source).encode(env)))
+    })
+}
+
+/// Formatted text of an error-free tree, newline-terminated unless empty
+fn format_tree(tree: &Tree, source: &str) -> String {
+    let root = tree.root_node();
+    let mut formatter = Formatter { source, lines: Vec::new() };
+    formatter.block(root.children(&mut root.walk()), 0);
+
+    let mut formatted = formatter.lines.join("\n");
+    if !formatted.is_empty() {
+        formatted.push('\n');
+    }
+    formatted
+}
+
+// Helper function to convert tree-sitter node to Elixir map
+fn convert_node_to_map<'env>(
+    node: &tree_sitter::Node,
+    source: &[u8],
+    env: Env<'env>
+) -> HashMap<String, Term<'env>> {
+    convert_node_to_map_with(node, source, env, &ConvertOptions::default())
+}
+
+// Convert a node to a map according to the given options
+// Nodes at the max_depth cutoff get "truncated" and "child_count" instead of children
+fn convert_node_to_map_with<'env>(
+    node: &tree_sitter::Node,
+    source: &[u8],
+    env: Env<'env>,
+    options: &ConvertOptions,
+) -> HashMap<String, Term<'env>> {
+    use rustler::Encoder;
+    
+    let mut result = HashMap::new();
+    
+    let start = node.start_position();
+    let end = node.end_position();
+    let text = node_text(node, source);
+    // A lossy decode only allocates when it had to replace invalid bytes
+    let text_valid = matches!(text, Cow::Borrowed(_));
+    
+    let (start_col, end_col) = if options.utf16_columns {
+        (
+            utf16_column(source, node.start_byte(), start.column),
+            utf16_column(source, node.end_byte(), end.column),
+        )
+    } else {
+        (start.column, end.column)
+    };
+    
+    // Use "type" to match Elixir typed struct expectations
+    result.insert("type".to_string(), node.kind().encode(env));
+    result.insert("start_row".to_string(), start.row.encode(env));
+    result.insert("start_col".to_string(), start_col.encode(env));
+    result.insert("end_row".to_string(), end.row.encode(env));
+    result.insert("end_col".to_string(), end_col.encode(env));
+    match options.max_text_bytes {
+        _ if !options.include_text => {}
+        Some(max_text_bytes) if text.len() > max_text_bytes => {
+            // Callers slice accumulated_input themselves using the byte range
+            result.insert("text".to_string(), rustler::types::atom::nil().encode(env));
+            result.insert("text_truncated".to_string(), true.encode(env));
+            result.insert("start_byte".to_string(), node.start_byte().encode(env));
+            result.insert("end_byte".to_string(), node.end_byte().encode(env));
+        }
+        _ => {
+            result.insert("text".to_string(), text.encode(env));
+        }
+    }
+    if !text_valid {
+        // The text above has U+FFFD in place of invalid bytes; the range locates the originals
+        result.insert("text_valid".to_string(), false.encode(env));
+        result.insert("start_byte".to_string(), node.start_byte().encode(env));
+        result.insert("end_byte".to_string(), node.end_byte().encode(env));
+    }
+    
+    // Node identity: node_id is only stable within a single tree, kind_id is the grammar symbol
+    result.insert("node_id".to_string(), node.id().encode(env));
+    result.insert("kind_id".to_string(), node.kind_id().encode(env));
+    // Subtree size including the node itself (O(1) in tree-sitter), for weighting changes
+    result.insert("descendant_count".to_string(), node.descendant_count().encode(env));
+    
+    // Add tree-sitter node metadata flags for error recovery
+    if options.include_error_flags {
+        result.insert("is_missing".to_string(), node.is_missing().encode(env));
+        result.insert("is_extra".to_string(), node.is_extra().encode(env));
+        result.insert("is_error".to_string(), node.is_error().encode(env));
+        result.insert("has_error".to_string(), node.has_error().encode(env));
+    }
+    
+    match options.max_depth {
+        Some(0) => {
+            let child_count = node.named_child_count();
+            if child_count > 0 {
+                result.insert("truncated".to_string(), true.encode(env));
+            }
+            result.insert("child_count".to_string(), child_count.encode(env));
+        }
+        _ => {
+            // Extract ALL named fields automatically using tree-sitter's field metadata
+            let child_options = ConvertOptions {
+                max_depth: options.max_depth.map(|depth| depth - 1),
+                ..*options
+            };
+            extract_all_node_fields(node, source, &mut result, env, &child_options);
+        }
+    }
+    
+    result
+}
+
+fn extract_all_node_fields<'env>(
+    node: &tree_sitter::Node,
+    source: &[u8],
+    result: &mut HashMap<String, Term<'env>>,
+    env: Env<'env>,
+    options: &ConvertOptions,
+) {
+    use rustler::Encoder;
+    use std::collections::HashMap as StdHashMap;
+    
+    let mut field_map: StdHashMap<String, Vec<HashMap<String, Term<'env>>>> = StdHashMap::new();
+    let mut unnamed_children: Vec<HashMap<String, Term<'env>>> = Vec::new();
+    
+    // Use cursor to iterate with field names
+    let mut cursor = node.walk();
+    let has_children = cursor.goto_first_child();
+    
+    if has_children {
+        loop {
+            let child = cursor.node();
+            
+            // Skip unnamed nodes (like punctuation)
+            if child.is_named() {
+                // Get field name for this child from cursor
+                if let Some(field_name) = cursor.field_name() {
+                    // Named field; the child also records the role it fills in its parent
+                    let mut child_map = convert_node_to_map_with(&child, source, env, options);
+                    child_map.insert("field".to_string(), field_name.encode(env));
+                    field_map
+                        .entry(field_name.to_string())
+                        .or_default()
+                        .push(child_map);
+                } else if options.include_children {
+                    // Unnamed child (e.g., children of program node)
+                    let child_map = convert_node_to_map_with(&child, source, env, options);
+                    unnamed_children.push(child_map);
+                }
+            }
+            
+            if !cursor.goto_next_sibling() {
+                break;
+            }
+        }
+    }
+    
+    // Add named fields to result - single value or list
+    for (field_name, values) in field_map {
+        if values.len() == 1 {
+            result.insert(field_name, values[0].clone().encode(env));
+        } else {
+            result.insert(field_name, values.encode(env));
+        }
+    }
+    
+    // Add unnamed children as "children" field if any exist
+    if !unnamed_children.is_empty() {
+        result.insert("children".to_string(), unnamed_children.encode(env));
+    }
+}
+
+// JSON counterpart of convert_node_to_map, producing the same field names
+fn convert_node_to_json(node: &tree_sitter::Node, source: &[u8]) -> serde_json::Value {
+    use serde_json::{json, Map, Value};
+
+    let start = node.start_position();
+    let end = node.end_position();
+
+    let mut result = Map::new();
+    result.insert("type".to_string(), json!(node.kind()));
+    result.insert("start_row".to_string(), json!(start.row));
+    result.insert("start_col".to_string(), json!(start.column));
+    result.insert("end_row".to_string(), json!(end.row));
+    result.insert("end_col".to_string(), json!(end.column));
+    let text = node_text(node, source);
+    if let Cow::Owned(_) = text {
+        result.insert("text_valid".to_string(), json!(false));
+        result.insert("start_byte".to_string(), json!(node.start_byte()));
+        result.insert("end_byte".to_string(), json!(node.end_byte()));
+    }
+    result.insert("text".to_string(), json!(text));
+    result.insert("node_id".to_string(), json!(node.id()));
+    result.insert("kind_id".to_string(), json!(node.kind_id()));
+    result.insert("descendant_count".to_string(), json!(node.descendant_count()));
+    result.insert("is_missing".to_string(), json!(node.is_missing()));
+    result.insert("is_extra".to_string(), json!(node.is_extra()));
+    result.insert("is_error".to_string(), json!(node.is_error()));
+    result.insert("has_error".to_string(), json!(node.has_error()));
+
+    let mut field_map: HashMap<String, Vec<Value>> = HashMap::new();
+    let mut unnamed_children = Vec::new();
+
+    let mut cursor = node.walk();
+    if cursor.goto_first_child() {
+        loop {
+            let child = cursor.node();
+            if child.is_named() {
+                let mut child_json = convert_node_to_json(&child, source);
+                match cursor.field_name() {
+                    Some(field_name) => {
+                        if let Value::Object(child_object) = &mut child_json {
+                            child_object.insert("field".to_string(), json!(field_name));
+                        }
+                        field_map.entry(field_name.to_string()).or_default().push(child_json)
+                    }
+                    None => unnamed_children.push(child_json),
+                }
+            }
+
+            if !cursor.goto_next_sibling() {
+                break;
+            }
+        }
+    }
+
+    // Single-valued fields collapse to the value itself, as in the map form
+    for (field_name, mut values) in field_map {
+        if values.len() == 1 {
+            result.insert(field_name, values.remove(0));
+        } else {
+            result.insert(field_name, Value::Array(values));
+        }
+    }
+
+    if !unnamed_children.is_empty() {
+        result.insert("children".to_string(), Value::Array(unnamed_children));
+    }
+
+    Value::Object(result)
+}
+
+/// Encode changed ranges from tree-sitter's incremental parsing
+/// Returns byte offsets and positions of modified AST subtrees
+fn changed_range_maps<'env>(
+    ranges: &[Range],
+    env: Env<'env>,
+) -> Vec<HashMap<String, Term<'env>>> {
+    use rustler::Encoder;
+    
+    ranges
+        .iter()
+        .map(|range| {
+            let mut map = HashMap::new();
+            map.insert("start_byte".to_string(), range.start_byte.encode(env));
+            map.insert("end_byte".to_string(), range.end_byte.encode(env));
+            map.insert("start_row".to_string(), range.start_point.row.encode(env));
+            map.insert("start_col".to_string(), range.start_point.column.encode(env));
+            map.insert("end_row".to_string(), range.end_point.row.encode(env));
+            map.insert("end_col".to_string(), range.end_point.column.encode(env));
+            map
+        })
+        .collect()
+}
+
+/// Sort ranges by start_byte and coalesce any that overlap or touch
+fn merge_ranges(mut ranges: Vec<Range>) -> Vec<Range> {
+    ranges.sort_by_key(|range| range.start_byte);
+
+    let mut merged: Vec<Range> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start_byte <= last.end_byte => {
+                if range.end_byte > last.end_byte {
+                    last.end_byte = range.end_byte;
+                    last.end_point = range.end_point;
+                }
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Extract changed AST nodes by finding nodes that overlap with changed ranges
+/// Returns the actual AST subtrees that were modified or added
+fn extract_changed_nodes<'env>(
+    new_tree: &Tree,
+    old_tree: &Tree,
+    source: &[u8],
+    env: Env<'env>,
+    options: &ConvertOptions,
+    container: Option<&str>,
+) -> Vec<HashMap<String, Term<'env>>> {
+    let ranges: Vec<Range> = new_tree.changed_ranges(old_tree).collect();
+    
+    // If we have changed ranges, use them to find changed nodes
+    if !ranges.is_empty() {
+        let mut changed_nodes = Vec::new();
+        let root = new_tree.root_node();
+        
+        // Multiple ranges (not necessarily adjacent) can map to the same node
+        let mut seen = HashSet::new();
+        
+        // For each changed range, find the smallest AST node that contains it
+        for range in ranges {
+            if let Some(node) = find_smallest_node_containing_range(&root, &range) {
+                // Only include named nodes (skip punctuation/whitespace)
+                if node.is_named() && seen.insert((node.start_byte(), node.end_byte(), node.kind_id())) {
+                    let node_map = convert_node_to_map_with(&node, source, env, options);
+                    changed_nodes.push(node_map);
+                }
+            }
+        }
+        
+        return changed_nodes;
+    }
+    
+    // If no changed ranges, fall back to diffing top-level statements between the trees
+    // The old tree has been edited, so its unchanged children already carry new-coordinate
+    // ranges; a new child with no unedited counterpart was added, moved, or rewritten.
+    // This covers appends (new commands) as well as edits in the middle of the document
+    let old_root = statement_container(old_tree, container);
+    let new_root = statement_container(new_tree, container);
+    
+    let mut old_cursor = old_root.walk();
+    let unchanged: HashSet<(usize, usize, u16)> = old_root
+        .named_children(&mut old_cursor)
+        .filter(|child| !child.has_changes())
+        .map(|child| (child.start_byte(), child.end_byte(), child.kind_id()))
+        .collect();
+    
+    let mut new_cursor = new_root.walk();
+    let children: Vec<_> = new_root.named_children(&mut new_cursor).collect();
+    children
+        .iter()
+        .filter(|child| !unchanged.contains(&(child.start_byte(), child.end_byte(), child.kind_id())))
+        .map(|child| convert_node_to_map_with(child, source, env, options))
+        .collect()
+}
+
+/// Node whose named children are the logical top-level statements for change detection
+/// The first (outermost) node of the given kind, falling back to the root when absent
+fn statement_container<'tree>(tree: &'tree Tree, kind: Option<&str>) -> tree_sitter::Node<'tree> {
+    let root = tree.root_node();
+    let Some(kind) = kind else {
+        return root;
+    };
+
+    let mut container = None;
+    walk_tree(root, |node| {
+        if container.is_none() && node.kind() == kind {
+            container = Some(node);
+        }
+        container.is_none()
+    });
+    container.unwrap_or(root)
+}
+
+/// Static name of a named bash node kind, or None if the grammar has no such kind
+fn bash_node_kind(name: &str) -> Option<&'static str> {
+    let language: tree_sitter::Language = tree_sitter_bash::LANGUAGE.into();
+    match language.id_for_node_kind(name, true) {
+        0 => None,
+        id => language.node_kind_for_id(id),
+    }
+}
+
+/// Find how many leading bytes can be evicted by dropping whole top-level statements
+/// Never evicts the last statement (it may still be extended) or anything past an error
+fn eviction_point(tree: &Tree, min_bytes: usize) -> usize {
+    let root = tree.root_node();
+    let mut cut = 0;
+    let mut cursor = root.walk();
+    
+    if cursor.goto_first_child() {
+        loop {
+            let child = cursor.node();
+            if child.has_error() {
+                break;
+            }
+            
+            // Cut at the start of the following statement so separators go too
+            match child.next_named_sibling() {
+                Some(next) if child.is_named() => cut = next.start_byte(),
+                Some(_) => {}
+                None => break,
+            }
+            
+            if cut >= min_bytes || !cursor.goto_next_sibling() {
+                break;
+            }
+        }
+    }
+    
+    cut
+}
+
+/// Find the smallest named node that fully contains the given range
+fn find_smallest_node_containing_range<'a>(
+    node: &tree_sitter::Node<'a>,
+    range: &Range,
+) -> Option<tree_sitter::Node<'a>> {
+    // Check if current node contains the range
+    if node.start_byte() > range.start_byte || node.end_byte() < range.end_byte {
+        return None;
+    }
+    
+    // Try to find a smaller child that contains the range
+    let mut cursor = node.walk();
+    let mut best_match = if node.is_named() { Some(*node) } else { None };
+    
+    if cursor.goto_first_child() {
+        loop {
+            let child = cursor.node();
+            // Recursively search for smaller containing node
+            if let Some(smaller) = find_smallest_node_containing_range(&child, range) {
+                best_match = Some(smaller);
+            }
+            
+            if !cursor.goto_next_sibling() {
+                break;
+            }
+        }
+    }
+    
+    best_match
+}
+
+/// Map an opening keyword/delimiter or compound node kind to its closing token
+fn closing_delimiter(kind: &str) -> Option<&'static str> {
+    match kind {
+        "if" | "if_statement" => Some("fi"),
+        "case" | "case_statement" => Some("esac"),
+        "do" | "do_group" | "for_statement" | "c_style_for_statement" | "while_statement" => Some("done"),
+        "{" | "${" | "compound_statement" | "expansion" => Some("}"),
+        "(" | "$(" | "<(" | ">(" | "subshell" | "command_substitution" | "process_substitution" => Some(")"),
+        "((" | "$((" | "arithmetic_expansion" => Some("))"),
+        "[[" => Some("]]"),
+        "[" => Some("]"),
+        "\"" | "string" => Some("\""),
+        "`" => Some("`"),
+        _ => None,
+    }
+}
+
+/// Find the innermost opening token among a node's direct children with no closer
+/// Returns the opener kind and the closing token it expects
+fn unclosed_opener(node: &tree_sitter::Node) -> Option<(&'static str, &'static str)> {
+    unclosed_opener_node(node).map(|(opener, closer)| (opener.kind(), closer))
+}
+
+/// Like unclosed_opener, but returns the opening token node itself
+fn unclosed_opener_node<'tree>(node: &tree_sitter::Node<'tree>) -> Option<(tree_sitter::Node<'tree>, &'static str)> {
+    let mut stack: Vec<(tree_sitter::Node<'tree>, &'static str)> = Vec::new();
+    let mut cursor = node.walk();
+
+    if cursor.goto_first_child() {
+        loop {
+            let child = cursor.node();
+            if !child.is_named() {
+                let kind = child.kind();
+                match stack.last() {
+                    Some((_, closer)) if *closer == kind => {
+                        stack.pop();
+                    }
+                    _ => {
+                        if let Some(closer) = closing_delimiter(kind) {
+                            stack.push((child, closer));
+                        }
+                    }
+                }
+            }
+
+            if !cursor.goto_next_sibling() {
+                break;
+            }
+        }
+    }
+
+    stack.pop()
+}
+
+/// Whether a token kind closes a compound construct or quote
+fn is_closing_token(kind: &str) -> bool {
+    matches!(kind, "fi" | "esac" | "done" | "}" | ")" | "))" | "]]" | "]" | "\"" | "`")
+}
+
+/// Node kind of the construct an opening token starts
+fn opened_construct(opener: &str) -> &'static str {
+    match opener {
+        "if" => "if_statement",
+        "case" => "case_statement",
+        "do" => "do_group",
+        "{" => "compound_statement",
+        "(" => "subshell",
+        "$(" | "`" => "command_substitution",
+        "<(" | ">(" => "process_substitution",
+        "${" => "expansion",
+        "$((" | "((" => "arithmetic_expansion",
+        "[[" | "[" => "test_command",
+        "\"" => "string",
+        _ => "unknown",
+    }
+}
+
+/// Source text of a node; bytes that are not valid UTF-8 become U+FFFD
+fn node_text<'a>(node: &tree_sitter::Node, source: &'a [u8]) -> Cow<'a, str> {
+    String::from_utf8_lossy(&source[node.byte_range()])
+}
+
+/// Summarize a command node as its name, argument texts, and byte range
+fn command_map<'env>(node: &tree_sitter::Node, source: &[u8], env: Env<'env>) -> HashMap<String, Term<'env>> {
+    use rustler::Encoder;
+
+    let name_node = node.child_by_field_name("name");
+    let name = name_node.map(|name| node_text(&name, source));
+
+    // A command_name wrapping anything but a plain word (e.g. $CMD) is resolved at runtime
+    let dynamic_name = name_node
+        .and_then(|name| name.named_child(0))
+        .is_some_and(|inner| inner.kind() != "word");
+
+    let mut cursor = node.walk();
+    let args: Vec<Cow<str>> = node
+        .children_by_field_name("argument", &mut cursor)
+        .map(|arg| node_text(&arg, source))
+        .collect();
+
+    let mut map = position_map(node, env);
+    map.insert("name".to_string(), name.as_deref().encode(env));
+    map.insert("args".to_string(), encode_texts(env, &args));
+    map.insert("dynamic_name".to_string(), dynamic_name.encode(env));
+    map
+}
+
+/// Whether an assignment is exported via `export NAME=...` or `declare -x NAME=...`
+fn is_exported_assignment(node: &tree_sitter::Node, source: &[u8]) -> bool {
+    let declaration = match node.parent() {
+        Some(parent) if parent.kind() == "declaration_command" => parent,
+        _ => return false,
+    };
+
+    let mut cursor = declaration.walk();
+    let exported = declaration.children(&mut cursor).any(|child| match child.kind() {
+        "export" => true,
+        // Flags such as -x or -rx appear as plain words before the assignments
+        "word" => {
+            let text = node_text(&child, source);
+            text.starts_with('-') && text.contains('x')
+        }
+        _ => false,
+    });
+    exported
+}
+
+/// Name of the variable referenced by a simple_expansion or expansion node
+/// Array subscripts like ${arr[1]} resolve to the array name
+fn expansion_variable_name<'a>(node: &tree_sitter::Node, source: &'a [u8]) -> Option<Cow<'a, str>> {
+    let mut cursor = node.walk();
+    let name = node.named_children(&mut cursor).find_map(|child| match child.kind() {
+        "variable_name" | "special_variable_name" => Some(node_text(&child, source)),
+        "subscript" => child.child_by_field_name("name").map(|name| node_text(&name, source)),
+        _ => None,
+    });
+    name
+}
+
+/// Source text between a node's opening and closing delimiter tokens
+/// Falls back to the whole node text when it has no delimiters (e.g. after error recovery)
+fn delimited_inner_text<'a>(node: &tree_sitter::Node, source: &'a [u8]) -> Cow<'a, str> {
+    let count = node.child_count();
+    match (node.child(0), count.checked_sub(1).and_then(|last| node.child(last))) {
+        (Some(open), Some(close)) if count >= 2 && !open.is_named() && !close.is_named() => {
+            String::from_utf8_lossy(&source[open.end_byte()..close.start_byte()])
+        }
+        _ => node_text(node, source),
+    }
+}
+
+/// Whether a pipeline is itself a stage of an enclosing pipeline
+/// tree-sitter-bash nests `a | b > f | c` as pipeline(redirected_statement(pipeline), c)
+fn is_nested_pipeline(node: &tree_sitter::Node) -> bool {
+    match node.parent() {
+        Some(parent) if parent.kind() == "pipeline" => true,
+        Some(parent) if parent.kind() == "redirected_statement" => {
+            parent.parent().is_some_and(|grandparent| grandparent.kind() == "pipeline")
+        }
+        _ => false,
+    }
+}
+
+/// Flatten a pipeline into its stages in source order, noting any `|&` operator
+fn collect_pipeline_stages<'tree>(
+    node: tree_sitter::Node<'tree>,
+    stages: &mut Vec<tree_sitter::Node<'tree>>,
+    uses_stderr_pipe: &mut bool,
+) {
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        if child.kind() == "|&" {
+            *uses_stderr_pipe = true;
+        }
+        if !child.is_named() {
+            continue;
+        }
+
+        let nested = match child.kind() {
+            "pipeline" => Some(child),
+            "redirected_statement" => child
+                .child_by_field_name("body")
+                .filter(|body| body.kind() == "pipeline"),
+            _ => None,
+        };
+
+        match nested {
+            Some(pipeline) => collect_pipeline_stages(pipeline, stages, uses_stderr_pipe),
+            None => stages.push(child),
+        }
+    }
+}
+
+/// The command node behind a statement, looking through redirects and negation
+fn stage_command<'tree>(node: &tree_sitter::Node<'tree>) -> Option<tree_sitter::Node<'tree>> {
+    match node.kind() {
+        "command" => Some(*node),
+        "redirected_statement" => node.child_by_field_name("body").and_then(|body| stage_command(&body)),
+        "negated_command" => node.named_child(0).and_then(|inner| stage_command(&inner)),
+        _ => None,
+    }
+}
+
+/// Collect outline symbols below a node, flattening non-symbol nodes in between
+/// Variable assignments only count as symbols at the top level of the script
+fn collect_outline<'env>(
+    node: &tree_sitter::Node,
+    source: &[u8],
+    env: Env<'env>,
+    top_level: bool,
+) -> Vec<HashMap<String, Term<'env>>> {
+    use rustler::Encoder;
+
+    let mut symbols = Vec::new();
+    let mut cursor = node.walk();
+
+    for child in node.named_children(&mut cursor) {
+        let header = |field: &str| {
+            child
+                .child_by_field_name(field)
+                .map(|part| format!("{} {}", child.child(0).map_or("", |keyword| keyword.kind()), node_text(&part, source)))
+        };
+
+        let symbol = match child.kind() {
+            "function_definition" => Some((
+                atoms::function(),
+                child.child_by_field_name("name").map(|name| node_text(&name, source).to_string()),
+            )),
+            "if_statement" => Some((atoms::if_block(), header("condition"))),
+            "case_statement" => Some((atoms::case_block(), header("value"))),
+            "for_statement" => Some((atoms::for_loop(), header("variable"))),
+            "c_style_for_statement" => Some((atoms::for_loop(), header("initializer"))),
+            "while_statement" => {
+                let is_until = child.child(0).is_some_and(|keyword| keyword.kind() == "until");
+                let kind = if is_until { atoms::until_loop() } else { atoms::while_loop() };
+                Some((kind, header("condition")))
+            }
+            // Prefix assignments (FOO=1 cmd) belong to the command, not the outline
+            "variable_assignment" if top_level && node.kind() != "command" => Some((
+                atoms::variable(),
+                child.child_by_field_name("name").map(|name| node_text(&name, source).to_string()),
+            )),
+            _ => None,
+        };
+
+        match symbol {
+            Some((kind, name)) => {
+                let mut map = position_map(&child, env);
+                map.insert("kind".to_string(), kind.encode(env));
+                map.insert("name".to_string(), name.unwrap_or_else(|| child.kind().to_string()).encode(env));
+                map.insert("children".to_string(), collect_outline(&child, source, env, false).encode(env));
+                symbols.push(map);
+            }
+            None => symbols.extend(collect_outline(&child, source, env, top_level)),
+        }
+    }
+
+    symbols
+}
+
+/// A resolved highlight span: byte range plus the innermost capture name
+struct HighlightSpan {
+    start_byte: usize,
+    end_byte: usize,
+    name: String,
+}
+
+/// Why highlighting could not produce spans
+enum HighlightFailure {
+    Query(tree_sitter::QueryError),
+    Highlight(tree_sitter_highlight::Error),
+}
+
+/// Resolve the highlights query argument, accepting :default (None) or a query string
+fn highlight_query_source(highlight_query: Term) -> NifResult<Option<String>> {
+    if let Ok(atom) = highlight_query.decode::<Atom>() {
+        if atom == atoms::default() {
+            return Ok(None);
+        }
+        return Err(Error::BadArg);
+    }
+    highlight_query.decode::<String>().map(Some)
+}
+
+/// Compile a highlights query, recognizing every capture name it uses
+fn highlight_config(query_source: &str) -> Result<HighlightConfiguration, tree_sitter::QueryError> {
+    let mut config = HighlightConfiguration::new(tree_sitter_bash::LANGUAGE.into(), "bash", query_source, "", "")?;
+    let names: Vec<String> = config.names().iter().map(|name| name.to_string()).collect();
+    config.configure(&names);
+    Ok(config)
+}
+
+/// Configuration for the bundled highlights query, compiled on first use
+/// None only if the bundled query fails to compile, which highlight_spans then reports
+fn default_highlight_config() -> Option<&'static HighlightConfiguration> {
+    static CONFIG: OnceLock<Option<HighlightConfiguration>> = OnceLock::new();
+    CONFIG.get_or_init(|| highlight_config(tree_sitter_bash::HIGHLIGHT_QUERY).ok()).as_ref()
+}
+
+/// Run tree-sitter-highlight over source and flatten nested captures
+/// query_source None uses the bundled query; each byte is attributed to its innermost capture
+/// and adjacent equal spans are merged
+fn highlight_spans(source: &[u8], query_source: Option<&str>) -> Result<Vec<HighlightSpan>, HighlightFailure> {
+    let compiled;
+    let config = match query_source.is_none().then(default_highlight_config).flatten() {
+        Some(config) => config,
+        None => {
+            compiled = highlight_config(query_source.unwrap_or(tree_sitter_bash::HIGHLIGHT_QUERY))
+                .map_err(HighlightFailure::Query)?;
+            &compiled
+        }
+    };
+    let names = config.names();
+
+    let mut highlighter = Highlighter::new();
+    let events = highlighter
+        .highlight(config, source, None, |_| None)
+        .map_err(HighlightFailure::Highlight)?;
+
+    let mut stack: Vec<usize> = Vec::new();
+    let mut spans: Vec<HighlightSpan> = Vec::new();
+    for event in events {
+        match event.map_err(HighlightFailure::Highlight)? {
+            HighlightEvent::HighlightStart(started) => stack.push(started.0),
+            HighlightEvent::HighlightEnd => {
+                stack.pop();
+            }
+            HighlightEvent::Source { start, end } => {
+                let Some(&index) = stack.last() else { continue };
+                match spans.last_mut() {
+                    Some(last) if last.end_byte == start && last.name == names[index] => last.end_byte = end,
+                    _ => spans.push(HighlightSpan {
+                        start_byte: start,
+                        end_byte: end,
+                        name: names[index].to_string(),
+                    }),
+                }
+            }
+        }
+    }
+
+    Ok(spans)
+}
+
+/// Encode highlight spans, shifting byte offsets by base_offset
+fn encode_highlight_spans<'env>(spans: &[HighlightSpan], base_offset: usize, env: Env<'env>) -> Vec<HashMap<String, Term<'env>>> {
+    use rustler::Encoder;
+
+    spans
+        .iter()
+        .map(|span| {
+            let mut map = HashMap::new();
+            map.insert("start_byte".to_string(), (span.start_byte + base_offset).encode(env));
+            map.insert("end_byte".to_string(), (span.end_byte + base_offset).encode(env));
+            map.insert("highlight_name".to_string(), span.name.encode(env));
+            map
+        })
+        .collect()
+}
+
+/// Build the error map for a failed highlight run
+fn highlight_error_map<'env>(failure: HighlightFailure, env: Env<'env>) -> HashMap<String, Term<'env>> {
+    use rustler::Encoder;
+
+    match failure {
+        HighlightFailure::Query(err) => query_error_map(&err, env),
+        HighlightFailure::Highlight(err) => {
+            let mut map = error_map(env, "highlight_error");
+            map.insert("message".to_string(), err.to_string().encode(env));
+            map
+        }
+    }
+}
+
+/// Estimate of how many bytes a reparse had to redo: the union of changed ranges and edited text
+/// Without an old tree everything was parsed from scratch
+fn reparsed_byte_count(new_tree: &Tree, old_tree: Option<&Tree>, input_len: usize, input_edits: &[InputEdit]) -> usize {
+    let old_tree = match old_tree {
+        Some(old_tree) => old_tree,
+        None => return input_len,
+    };
+
+    let mut spans: Vec<(usize, usize)> = new_tree
+        .changed_ranges(old_tree)
+        .map(|range| (range.start_byte, range.end_byte))
+        .chain(edited_byte_span(input_edits))
+        .map(|(start, end)| (start.min(input_len), end.min(input_len)))
+        .collect();
+    spans.sort_unstable();
+
+    let mut total = 0;
+    let mut covered_to = 0;
+    for (start, end) in spans {
+        let start = start.max(covered_to);
+        if end > start {
+            total += end - start;
+            covered_to = end;
+        }
+    }
+    total
+}
+
+/// Union of the byte ranges written by a sequence of edits, in post-edit coordinates
+/// Earlier spans are carried through later edits so eviction shifts are accounted for
+fn edited_byte_span(input_edits: &[InputEdit]) -> Option<(usize, usize)> {
+    let mut span: Option<(usize, usize)> = None;
+
+    for edit in input_edits {
+        let carry = |byte: usize| {
+            if byte >= edit.old_end_byte {
+                byte - edit.old_end_byte + edit.new_end_byte
+            } else {
+                byte.min(edit.new_end_byte)
+            }
+        };
+        let written = (edit.start_byte, edit.new_end_byte);
+        span = Some(match span {
+            Some((start, end)) => (carry(start).min(written.0), carry(end).max(written.1)),
+            None => written,
+        });
+    }
+
+    span
+}
+
+/// Widen start..end to cover every top-level statement it overlaps
+/// Statements are self-contained, so highlighting the slice alone matches a full run
+fn statement_window(tree: &Tree, start: usize, end: usize) -> (usize, usize) {
+    let root = tree.root_node();
+    let mut window = (start.min(root.end_byte()), end.min(root.end_byte()));
+    let mut cursor = root.walk();
+
+    for child in root.children(&mut cursor) {
+        if child.end_byte() >= start && child.start_byte() <= end {
+            window.0 = window.0.min(child.start_byte());
+            window.1 = window.1.max(child.end_byte());
+        }
+    }
+
+    window
+}
+
+/// Settings and input recovered from an export_state binary
+struct ExportedState {
+    input: Vec<u8>,
+    max_buffer_size: usize,
+    window_bytes: Option<usize>,
+    timeout_micros: u64,
+    cancellable: bool,
+    convert_options: ConvertOptions,
+    auto_flush: bool,
+    statement_container: Option<&'static str>,
+    include_timing: bool,
+}
+
+/// Decode an export_state binary, returning None for anything malformed or from another version
+fn decode_exported_state(state: &str) -> Option<ExportedState> {
+    let value: serde_json::Value = serde_json::from_str(state).ok()?;
+    if value.get("version")?.as_u64()? != 1 {
+        return None;
+    }
+
+    let window_bytes = match value.get("window_bytes")? {
+        serde_json::Value::Null => None,
+        window => Some(window.as_u64()? as usize),
+    };
+
+    let input = match value.get("input")? {
+        serde_json::Value::Null => value
+            .get("input_bytes")?
+            .as_array()?
+            .iter()
+            .map(|byte| byte.as_u64().and_then(|byte| u8::try_from(byte).ok()))
+            .collect::<Option<Vec<u8>>>()?,
+        input => input.as_str()?.as_bytes().to_vec(),
+    };
+
+    // Conversion flags were added later; older exports fall back to the defaults
+    let flag = |key: &str| value.get(key).and_then(serde_json::Value::as_bool).unwrap_or(true);
+    let convert_options = ConvertOptions {
+        include_error_flags: flag("include_error_flags"),
+        include_children: flag("include_children"),
+        include_text: flag("include_text"),
+        ..Default::default()
+    };
+
+    Some(ExportedState {
+        input,
+        max_buffer_size: value.get("max_buffer_size")?.as_u64()? as usize,
+        window_bytes,
+        timeout_micros: value.get("timeout_micros")?.as_u64()?,
+        cancellable: value.get("cancellable")?.as_bool()?,
+        convert_options,
+        auto_flush: value.get("auto_flush").and_then(serde_json::Value::as_bool).unwrap_or(false),
+        statement_container: value.get("statement_container").and_then(serde_json::Value::as_str).and_then(bash_node_kind),
+        include_timing: value.get("include_timing").and_then(serde_json::Value::as_bool).unwrap_or(false),
+    })
+}
+
+/// Node counts and depth gathered by tree_stats
+#[derive(Default)]
+struct TreeStats {
+    node_count: usize,
+    named_node_count: usize,
+    max_depth: usize,
+    error_count: usize,
+}
+
+/// Gather TreeStats in one cursor walk (no per-node allocation)
+fn tree_stats(root: tree_sitter::Node) -> TreeStats {
+    let mut stats = TreeStats::default();
+    let mut cursor = root.walk();
+    let mut depth = 0;
+
+    loop {
+        let node = cursor.node();
+        stats.node_count += 1;
+        if node.is_named() {
+            stats.named_node_count += 1;
+        }
+        if node.is_error() || node.is_missing() {
+            stats.error_count += 1;
+        }
+        stats.max_depth = stats.max_depth.max(depth);
+
+        if cursor.goto_first_child() {
+            depth += 1;
+            continue;
+        }
+
+        loop {
+            if depth == 0 {
+                return stats;
+            }
+            if cursor.goto_next_sibling() {
+                break;
+            }
+            cursor.goto_parent();
+            depth -= 1;
+        }
+    }
+}
+
+/// Decode new_parser_with_opts options; missing keys keep their defaults
+fn convert_options_from_term(opts: Term) -> NifResult<ConvertOptions> {
+    if !opts.is_map() {
+        return Err(Error::BadArg);
+    }
+
+    let mut options = ConvertOptions::default();
+    for (key, flag) in [
+        (atoms::include_error_flags(), &mut options.include_error_flags),
+        (atoms::include_children(), &mut options.include_children),
+        (atoms::include_text(), &mut options.include_text),
+    ] {
+        if let Ok(value) = opts.map_get(key) {
+            *flag = value.decode()?;
+        }
+    }
+
+    Ok(options)
+}
+
+/// Command name (basename, looking through sudo-style wrappers) and unquoted arguments
+/// Returns None for commands whose name is only known at runtime
+fn effective_command<'a>(node: &tree_sitter::Node, source: &'a [u8]) -> Option<(Cow<'a, str>, Vec<Cow<'a, str>>)> {
+    let name_node = node.child_by_field_name("name")?;
+    if name_node.named_child(0).is_some_and(|inner| inner.kind() != "word") {
+        return None;
+    }
+
+    let mut cursor = node.walk();
+    let mut words: Vec<Cow<str>> = std::iter::once(node_text(&name_node, source))
+        .chain(node.children_by_field_name("argument", &mut cursor).map(|arg| unquoted_text(&arg, source)))
+        .collect();
+
+    // `sudo rm -rf /` is as dangerous as `rm -rf /`; skip the wrapper and its own flags
+    while let Some(first) = words.first() {
+        let base = first.rsplit('/').next().unwrap_or(first);
+        if !matches!(base, "sudo" | "doas" | "nohup" | "exec" | "command" | "builtin" | "time") {
+            break;
+        }
+        words.remove(0);
+        while words.first().is_some_and(|word| word.starts_with('-')) {
+            words.remove(0);
+        }
+    }
+
+    let rest = words.split_off(1);
+    let name = match words.pop()? {
+        Cow::Borrowed(name) => Cow::Borrowed(name.rsplit('/').next().unwrap_or(name)),
+        Cow::Owned(name) => Cow::Owned(name.rsplit('/').next().unwrap_or(&name).to_string()),
+    };
+    Some((name, rest))
+}
+
+/// Target user and escalated words for a privilege-escalation tool's arguments
+/// Options that take a value are skipped together with it; su's command is its -c string
+fn escalated_command<'a>(tool: &str, args: &[&'a str]) -> (Option<&'a str>, Vec<&'a str>) {
+    let (valued, user_flags): (&[&str], &[&str]) = match tool {
+        "sudo" => (
+            &["-u", "--user", "-g", "--group", "-C", "--close-from", "-h", "--host", "-p", "--prompt",
+              "-D", "--chdir", "-r", "--role", "-t", "--type", "-U", "--other-user"],
+            &["-u", "--user"],
+        ),
+        "doas" => (&["-u", "-C"], &["-u"]),
+        "pkexec" => (&["--user"], &["--user"]),
+        "su" => (&["-c", "--command", "-s", "--shell", "-g", "--group", "-G", "--supp-group"], &[]),
+        _ => (&[], &[]),
+    };
+
+    let mut user = None;
+    let mut su_command = None;
+    let mut index = 0;
+    while let Some(arg) = args.get(index) {
+        if *arg == "--" {
+            index += 1;
+            break;
+        }
+        // A lone `-` is su's login-shell flag, not an operand
+        if tool == "su" && *arg == "-" {
+            index += 1;
+            continue;
+        }
+        if arg.len() < 2 || !arg.starts_with('-') {
+            break;
+        }
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if arg.starts_with("--") => (flag, Some(value)),
+            _ => (*arg, None),
+        };
+        let mut value = inline_value;
+        if inline_value.is_none() && valued.contains(&flag) {
+            index += 1;
+            value = args.get(index).copied();
+        }
+        if user_flags.contains(&flag) {
+            user = value;
+        }
+        if tool == "su" && matches!(flag, "-c" | "--command") {
+            su_command = value;
+        }
+        index += 1;
+    }
+
+    let rest = &args[index.min(args.len())..];
+    if tool == "su" {
+        // su takes the user as its first operand; the command only comes from -c
+        return (rest.first().copied(), su_command.into_iter().collect());
+    }
+    (user, rest.to_vec())
+}
+
+/// Built-in rules keyed on command name and arguments
+fn builtin_command_risks(name: &str, args: &[Cow<str>]) -> Vec<(Atom, Atom)> {
+    let mut risks = Vec::new();
+    let short_flags = |letter: char| {
+        args.iter().any(|arg| arg.starts_with('-') && !arg.starts_with("--") && arg.contains(letter))
+    };
+
+    match name {
+        "rm" => {
+            let recursive = short_flags('r') || short_flags('R') || args.iter().any(|arg| arg == "--recursive");
+            let force = short_flags('f') || args.iter().any(|arg| arg == "--force");
+            if recursive && force {
+                risks.push((atoms::rm_rf(), atoms::critical()));
+            }
+        }
+        "dd" => {
+            let to_device = args
+                .iter()
+                .any(|arg| arg.strip_prefix("of=").is_some_and(is_block_device));
+            if to_device {
+                risks.push((atoms::device_write(), atoms::critical()));
+            } else {
+                risks.push((atoms::dd(), atoms::medium()));
+            }
+        }
+        "chmod" if args.iter().any(|arg| matches!(&**arg, "777" | "0777" | "a+rwx")) => {
+            risks.push((atoms::chmod_777(), atoms::high()));
+        }
+        _ if name == "mkfs" || name.starts_with("mkfs.") => {
+            risks.push((atoms::mkfs(), atoms::critical()));
+        }
+        _ => {}
+    }
+
+    risks
+}
+
+/// Whether a path names a raw disk device such as /dev/sda or /dev/nvme0n1
+fn is_block_device(path: &str) -> bool {
+    ["/dev/sd", "/dev/hd", "/dev/vd", "/dev/xvd", "/dev/nvme", "/dev/mmcblk", "/dev/disk"]
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+}
+
+/// Whether a command name is a shell interpreter
+fn is_shell(name: &str) -> bool {
+    matches!(name, "sh" | "bash" | "zsh" | "dash" | "ksh")
+}
+
+/// Source text of a word with one level of surrounding quotes removed
+fn unquoted_text<'a>(node: &tree_sitter::Node, source: &'a [u8]) -> Cow<'a, str> {
+    let bytes = &source[node.byte_range()];
+    match node.kind() {
+        "raw_string" | "string" if bytes.len() >= 2 => String::from_utf8_lossy(&bytes[1..bytes.len() - 1]),
+        _ => String::from_utf8_lossy(bytes),
+    }
+}
+
+/// Match text against a glob pattern supporting `*` and `?`
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last `*` swallow one more character and retry
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, t));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Command name and the argument words bash word-splits, for a command or `[ ]` test
+/// Returns None for other nodes; `[[ ]]` operands are not split, so those tests are skipped too
+fn split_words<'a, 'tree>(
+    node: &tree_sitter::Node<'tree>,
+    source: &'a [u8],
+) -> Option<(Option<Cow<'a, str>>, Vec<tree_sitter::Node<'tree>>)> {
+    match node.kind() {
+        "command" => {
+            let mut cursor = node.walk();
+            let words: Vec<_> = node.children_by_field_name("argument", &mut cursor).collect();
+            Some((node.child_by_field_name("name").map(|name| node_text(&name, source)), words))
+        }
+        "test_command" if node.child(0).is_some_and(|open| open.kind() == "[") => {
+            Some((Some(Cow::Borrowed("[")), test_operands(node)))
+        }
+        _ => None,
+    }
+}
+
+/// Top-level parts of a word: the pieces of a concatenation, or the word itself
+fn word_parts<'tree>(word: &tree_sitter::Node<'tree>) -> Vec<tree_sitter::Node<'tree>> {
+    let mut cursor = word.walk();
+    match word.kind() {
+        "concatenation" => word.named_children(&mut cursor).collect(),
+        _ => vec![*word],
+    }
+}
+
+/// Expansions of `$#`, `$?`, `$$` and `$!` are always single numbers, so splitting them is harmless
+fn is_word_safe_expansion(node: &tree_sitter::Node, source: &[u8]) -> bool {
+    node.kind() == "simple_expansion" && matches!(expansion_variable_name(node, source).as_deref(), Some("#" | "?" | "$" | "!"))
+}
+
+/// Whether a command's exit status is consumed by `&&`/`||`, `!`, or an if/while/until condition
+fn exit_status_checked(node: &tree_sitter::Node) -> bool {
+    let mut current = *node;
+    while let Some(parent) = current.parent() {
+        match parent.kind() {
+            "list" | "negated_command" => return true,
+            "if_statement" | "elif_clause" | "while_statement" | "until_statement" => {
+                let mut cursor = parent.walk();
+                let is_condition = parent
+                    .children_by_field_name("condition", &mut cursor)
+                    .any(|condition| condition.id() == current.id());
+                return is_condition;
+            }
+            "redirected_statement" => current = parent,
+            _ => return false,
+        }
+    }
+    false
+}
+
+/// `-r`, `-R`, a short-flag cluster containing either, or `--recursive`
+fn is_recursive_flag(arg: &str) -> bool {
+    arg == "--recursive" || (arg.starts_with('-') && !arg.starts_with("--") && arg.contains(['r', 'R']))
+}
+
+/// Whether a word begins with an expansion immediately followed by `/` (as in "$dir/" or ${dir}/x)
+/// Expansions with a `:?`/`?` guard or a default value cannot expand to empty and are not reported
+fn starts_with_unguarded_directory(word: &tree_sitter::Node, source: &[u8]) -> bool {
+    let mut first = *word;
+    while matches!(first.kind(), "concatenation" | "string") {
+        first = match first.named_child(0) {
+            Some(child) => child,
+            None => return false,
+        };
+    }
+    // A string's first named child must also be its first content, not text after a literal prefix
+    let at_start = first.start_byte() == word.start_byte()
+        || (first.start_byte() == word.start_byte() + 1 && source[word.start_byte()..].starts_with(b"\""));
+    let guarded = node_text(&first, source).contains(['?', '-', '=', '+']);
+
+    at_start
+        && matches!(first.kind(), "simple_expansion" | "expansion")
+        && !guarded
+        && source[first.end_byte()..].starts_with(b"/")
+}
+
+/// Operand words of a test command, looking through unary/binary expressions
+fn test_operands<'tree>(node: &tree_sitter::Node<'tree>) -> Vec<tree_sitter::Node<'tree>> {
+    let mut operands = Vec::new();
+    let mut cursor = node.walk();
+    for child in node.named_children(&mut cursor) {
+        match child.kind() {
+            "binary_expression" | "unary_expression" | "parenthesized_expression" | "negation_expression" => {
+                operands.extend(test_operands(&child));
+            }
+            _ => operands.push(child),
+        }
+    }
+    operands
+}
+
+/// Operator texts used in a test command's expression, in source order
+/// Command substitutions are not entered, since their tests are reported on their own
+fn test_operators<'a>(node: &tree_sitter::Node, source: &'a [u8]) -> Vec<Cow<'a, str>> {
+    let mut operators = Vec::new();
+    walk_tree(*node, |child| {
+        match child.kind() {
+            "command_substitution" | "process_substitution" => return false,
+            "binary_expression" | "unary_expression" | "postfix_expression" => {
+                operators.extend(child.child_by_field_name("operator"));
+            }
+            "negation_expression" => operators.extend(child.child(0)),
+            _ => {}
+        }
+        true
+    });
+    // Outer expressions are visited before their operands, so restore source order
+    operators.sort_by_key(|operator| operator.start_byte());
+    operators.iter().map(|operator| node_text(operator, source)).collect()
+}
+
+/// Distinct variable names referenced inside an arithmetic construct, in first-use order
+/// Bare words such as `i++` are scanned for identifiers, since the grammar leaves them unsplit
+fn arithmetic_variables<'a>(node: &tree_sitter::Node, source: &'a [u8]) -> Vec<Cow<'a, str>> {
+    let mut variables: Vec<Cow<'a, str>> = Vec::new();
+    let mut add = |name: Cow<'a, str>| {
+        if !variables.contains(&name) {
+            variables.push(name);
+        }
+    };
+    walk_tree(*node, |child| {
+        match child.kind() {
+            "command_substitution" | "process_substitution" => return false,
+            "variable_name" => add(node_text(&child, source)),
+            "word" => {
+                // Identifiers are ASCII, so the raw bytes can be split without decoding
+                source[child.byte_range()]
+                    .split(|&byte| !(byte.is_ascii_alphanumeric() || byte == b'_'))
+                    .filter(|part| part.first().is_some_and(|&byte| byte.is_ascii_alphabetic() || byte == b'_'))
+                    .filter_map(|part| std::str::from_utf8(part).ok())
+                    .for_each(|part| add(Cow::Borrowed(part)));
+            }
+            _ => {}
+        }
+        true
+    });
+    variables
+}
+
+/// Compile a query against the bash grammar, rejecting predicates that cannot be evaluated
+/// tree-sitter evaluates the standard text predicates itself (regexes are compiled once here);
+/// any other `#name?` would otherwise be ignored and let every match through
+fn compile_query(source: &str) -> Result<Query, tree_sitter::QueryError> {
+    let bash_language = tree_sitter_bash::LANGUAGE.into();
+    let query = Query::new(&bash_language, source)?;
+
+    for pattern_index in 0..query.pattern_count() {
+        let unsupported = query
+            .general_predicates(pattern_index)
+            .iter()
+            .find(|predicate| predicate.operator.ends_with('?'));
+        if let Some(predicate) = unsupported {
+            let offset = query.start_byte_for_pattern(pattern_index);
+            let point = byte_to_point(source.as_bytes(), offset);
+            return Err(tree_sitter::QueryError {
+                row: point.row,
+                column: point.column,
+                offset,
+                message: format!("unsupported predicate #{}", predicate.operator),
+                kind: tree_sitter::QueryErrorKind::Predicate,
+            });
+        }
+    }
+
+    Ok(query)
+}
+
+/// Build the error map for a query that failed to compile
+fn query_error_map<'env>(err: &tree_sitter::QueryError, env: Env<'env>) -> HashMap<String, Term<'env>> {
+    use rustler::Encoder;
+    use tree_sitter::QueryErrorKind;
+
+    let kind = match err.kind {
+        QueryErrorKind::Syntax => atoms::syntax(),
+        QueryErrorKind::NodeType => atoms::node_type(),
+        QueryErrorKind::Field => atoms::field(),
+        QueryErrorKind::Capture => atoms::capture(),
+        QueryErrorKind::Predicate => atoms::predicate(),
+        QueryErrorKind::Structure => atoms::structure(),
+        QueryErrorKind::Language => atoms::language(),
+    };
+
+    let mut map = error_map(env, "query_error");
+    map.insert("kind".to_string(), kind.encode(env));
+    map.insert("message".to_string(), err.message.encode(env));
+    map.insert("offset".to_string(), err.offset.encode(env));
+    map.insert("row".to_string(), err.row.encode(env));
+    map.insert("column".to_string(), err.column.encode(env));
+    map
+}
+
+/// The outermost named node spanning exactly start_byte..end_byte
+/// Outermost so that e.g. a statement wins over a command_name with the same span
+fn node_with_exact_range(tree: &Tree, start_byte: usize, end_byte: usize) -> Option<tree_sitter::Node<'_>> {
+    if start_byte > end_byte {
+        return None;
+    }
+
+    let mut node = tree.root_node().named_descendant_for_byte_range(start_byte, end_byte)?;
+    if node.start_byte() != start_byte || node.end_byte() != end_byte {
+        return None;
+    }
+
+    while let Some(parent) = node.parent() {
+        if parent.start_byte() != start_byte || parent.end_byte() != end_byte || parent.kind() == "program" {
+            break;
+        }
+        node = parent;
+    }
+    Some(node)
+}
+
+/// Completeness check behind is_complete
+/// Only the last top-level statement matters; earlier errors are already reportable
+fn input_is_complete(tree: &Tree, source: &[u8]) -> bool {
+    // An odd number of trailing backslashes escapes the final newline
+    let last_line = source.strip_suffix(b"\n").unwrap_or(source);
+    let trailing_backslashes = last_line.iter().rev().take_while(|&&byte| byte == b'\\').count();
+    if trailing_backslashes % 2 == 1 {
+        return false;
+    }
+
+    let root = tree.root_node();
+    let mut cursor = root.walk();
+    // Recovery can mark a trailing ERROR as extra, so only comments are skipped
+    let last = root.children(&mut cursor).filter(|child| !child.is_extra() || child.is_error()).last();
+    let Some(last) = last else {
+        return true;
+    };
+    if last.has_error() {
+        return false;
+    }
+
+    // A heredoc cut off by EOF parses cleanly but has an empty heredoc_end
+    let mut open_heredoc = false;
+    walk_tree(last, |node| {
+        if node.kind() == "heredoc_end" && node.start_byte() == node.end_byte() {
+            open_heredoc = true;
+        }
+        !open_heredoc
+    });
+    !open_heredoc
+}
+
+/// Line-based pretty printer behind the format NIF
+struct Formatter<'a> {
+    source: &'a str,
+    lines: Vec<String>,
+}
+
+impl<'a> Formatter<'a> {
+    /// Source text of a node (the formatter only runs on valid UTF-8)
+    fn text(&self, node: &tree_sitter::Node) -> &'a str {
+        &self.source[node.byte_range()]
+    }
+
+    /// Emit a sequence of sibling statements, keeping comments, `&` and single blank lines
+    fn block<'tree>(&mut self, items: impl IntoIterator<Item = tree_sitter::Node<'tree>>, indent: usize) {
+        let mut previous_end_row: Option<usize> = None;
+
+        for item in items {
+            match item.kind() {
+                "&" => self.append(" &"),
+                _ if !item.is_named() => {}
+                "comment" if previous_end_row == Some(item.start_position().row) => {
+                    // Trailing comment on the same line as the previous statement
+                    let text = self.text(&item);
+                    self.append(&format!(" {}", text));
+                }
+                _ => {
+                    if previous_end_row.is_some_and(|row| item.start_position().row > row + 1) {
+                        self.lines.push(String::new());
+                    }
+                    self.statement(&item, indent);
+                }
+            }
+            previous_end_row = Some(item.end_position().row);
+        }
+    }
+
+    /// Emit one statement, restructuring compound forms and copying the rest verbatim
+    fn statement(&mut self, node: &tree_sitter::Node, indent: usize) {
+        match node.kind() {
+            "if_statement" => self.if_statement(node, indent),
+            "for_statement" | "c_style_for_statement" | "while_statement" => {
+                let Some((header, body, comments)) = self.header(node) else {
+                    return self.verbatim(node, indent);
+                };
+                self.line(indent, &format!("{}; do", header));
+                self.block(comments.into_iter().chain(inner_children(&body)), indent + 1);
+                self.line(indent, "done");
+            }
+            "function_definition" => match self.header(node) {
+                // Redirects after the body (`f() { ...; } > log`) are kept; heredocs stay verbatim
+                Some((header, body, comments))
+                    if body.kind() == "compound_statement" && !has_heredoc_redirect(node) =>
+                {
+                    self.line(indent, &format!("{} {{", header));
+                    self.block(comments.into_iter().chain(inner_children(&body)), indent + 1);
+                    self.line(indent, "}");
+                    let redirects = self.source[body.end_byte()..node.end_byte()].trim();
+                    if !redirects.is_empty() {
+                        self.append(&format!(" {}", redirects));
+                    }
+                }
+                _ => self.verbatim(node, indent),
+            },
+            "compound_statement" => {
+                self.line(indent, "{");
+                self.block(inner_children(node), indent + 1);
+                self.line(indent, "}");
+            }
+            "case_statement" => self.case_statement(node, indent),
+            "redirected_statement" => {
+                match node.child_by_field_name("body") {
+                    Some(body) if !has_heredoc_redirect(node) && is_compound(&body) => {
+                        self.statement(&body, indent);
+                        let redirects = self.source[body.end_byte()..node.end_byte()].trim();
+                        self.append(&format!(" {}", redirects));
+                    }
+                    _ => self.verbatim(node, indent),
+                }
+            }
+            _ => self.verbatim(node, indent),
+        }
+    }
+
+    /// Source of a loop or function header (up to its last token before the body, minus a `;`),
+    /// its body, and the comments after that token, which callers move to the top of the body
+    /// (left in place they would swallow the re-emitted `; do`/` {`)
+    /// None (copy verbatim) if there's no body or a comment sits inside the header
+    fn header<'tree>(
+        &self,
+        node: &tree_sitter::Node<'tree>,
+    ) -> Option<(&'a str, tree_sitter::Node<'tree>, Vec<tree_sitter::Node<'tree>>)> {
+        let body = node.child_by_field_name("body")?;
+        let mut cursor = node.walk();
+        let (comments, parts): (Vec<_>, Vec<_>) = node
+            .children(&mut cursor)
+            .take_while(|child| child.id() != body.id())
+            .partition(|child| child.is_extra());
+        let last = parts.iter().rev().find(|part| part.kind() != ";")?;
+        if comments.iter().any(|comment| comment.start_byte() < last.end_byte()) {
+            return None;
+        }
+        Some((&self.source[node.start_byte()..last.end_byte()], body, comments))
+    }
+
+    fn if_statement(&mut self, node: &tree_sitter::Node, indent: usize) {
+        let mut cursor = node.walk();
+        let children: Vec<_> = node.children(&mut cursor).collect();
+        let clauses = children
+            .iter()
+            .position(|child| matches!(child.kind(), "elif_clause" | "else_clause" | "fi"))
+            .unwrap_or(children.len());
+
+        self.conditional_clause("if", &children[..clauses], indent);
+        for clause in &children[clauses..] {
+            match clause.kind() {
+                "elif_clause" => {
+                    let mut clause_cursor = clause.walk();
+                    let parts: Vec<_> = clause.children(&mut clause_cursor).collect();
+                    self.conditional_clause("elif", &parts, indent);
+                }
+                "else_clause" => {
+                    self.line(indent, "else");
+                    self.block(inner_children(clause), indent + 1);
                 }
+                _ => {}
             }
-            
-            if !cursor.goto_next_sibling() {
-                break;
+        }
+        self.line(indent, "fi");
+    }
+
+    /// Emit `keyword <condition>; then` followed by the indented body
+    /// Comments within the condition move to the top of the body
+    fn conditional_clause(&mut self, keyword: &str, parts: &[tree_sitter::Node], indent: usize) {
+        let then = parts.iter().position(|part| part.kind() == "then").unwrap_or(parts.len());
+        let (comments, condition): (Vec<_>, Vec<_>) = parts[..then]
+            .iter()
+            .filter(|part| part.is_named())
+            .partition(|part| part.is_extra());
+        let condition: Vec<&str> = condition.iter().map(|part| self.text(part)).collect();
+
+        self.line(indent, &format!("{} {}; then", keyword, condition.join("; ")));
+        let body = parts[(then + 1).min(parts.len())..].iter();
+        self.block(comments.into_iter().chain(body.copied()), indent + 1);
+    }
+
+    fn case_statement(&mut self, node: &tree_sitter::Node, indent: usize) {
+        let subject = node.child_by_field_name("value").map_or("", |value| self.text(&value));
+        self.line(indent, &format!("case {} in", subject));
+
+        let mut cursor = node.walk();
+        for item in node.named_children(&mut cursor) {
+            if item.kind() != "case_item" {
+                if item.kind() == "comment" {
+                    self.verbatim(&item, indent + 1);
+                }
+                continue;
+            }
+
+            let mut item_cursor = item.walk();
+            let mut patterns = Vec::new();
+            let mut body = Vec::new();
+            let mut termination = ";;";
+            if item_cursor.goto_first_child() {
+                loop {
+                    let child = item_cursor.node();
+                    match (item_cursor.field_name(), child.kind()) {
+                        (Some("value"), _) => patterns.push(self.text(&child)),
+                        (_, kind @ (";;" | ";&" | ";;&")) => termination = kind,
+                        (_, ")" | "|" | "(") => {}
+                        _ => body.push(child),
+                    }
+                    if !item_cursor.goto_next_sibling() {
+                        break;
+                    }
+                }
             }
+
+            self.line(indent + 1, &format!("{})", patterns.join("|")));
+            self.block(body, indent + 2);
+            self.line(indent + 2, termination);
         }
+
+        self.line(indent, "esac");
     }
-    
-    // Add named fields to result - single value or list
-    for (field_name, values) in field_map {
-        if values.len() == 1 {
-            result.insert(field_name, values[0].clone().encode(env));
-        } else {
-            result.insert(field_name, values.encode(env));
+
+    /// Copy a node's source text; only the first line is re-indented (heredocs stay intact)
+    fn verbatim(&mut self, node: &tree_sitter::Node, indent: usize) {
+        let text = self.text(node);
+        let mut lines = text.split('\n');
+        if let Some(first) = lines.next() {
+            self.line(indent, first);
         }
+        self.lines.extend(lines.map(str::to_string));
     }
-    
-    // Add unnamed children as "children" field if any exist
-    if !unnamed_children.is_empty() {
-        result.insert("children".to_string(), unnamed_children.encode(env));
+
+    fn line(&mut self, indent: usize, text: &str) {
+        self.lines.push(format!("{}{}", "  ".repeat(indent), text));
+    }
+
+    fn append(&mut self, text: &str) {
+        match self.lines.last_mut() {
+            Some(last) => last.push_str(text),
+            None => self.lines.push(text.trim_start().to_string()),
+        }
     }
 }
 
-/// Extract changed ranges from tree-sitter's incremental parsing
-/// Returns byte offsets and positions of modified AST subtrees
-fn extract_changed_ranges<'env>(
-    new_tree: &Tree,
-    old_tree: &Tree,
-    env: Env<'env>,
-) -> Vec<HashMap<String, Term<'env>>> {
-    use rustler::Encoder;
-    
-    let ranges: Vec<Range> = new_tree.changed_ranges(old_tree).collect();
-    
-    ranges
-        .iter()
-        .map(|range| {
-            let mut map = HashMap::new();
-            map.insert("start_byte".to_string(), range.start_byte.encode(env));
-            map.insert("end_byte".to_string(), range.end_byte.encode(env));
-            map.insert("start_row".to_string(), range.start_point.row.encode(env));
-            map.insert("start_col".to_string(), range.start_point.column.encode(env));
-            map.insert("end_row".to_string(), range.end_point.row.encode(env));
-            map.insert("end_col".to_string(), range.end_point.column.encode(env));
-            map
-        })
+/// Children of a delimited block (do_group, compound_statement, else_clause) minus its keywords
+fn inner_children<'tree>(node: &tree_sitter::Node<'tree>) -> Vec<tree_sitter::Node<'tree>> {
+    let mut cursor = node.walk();
+    node.children(&mut cursor)
+        .filter(|child| !matches!(child.kind(), "do" | "done" | "{" | "}" | "else"))
         .collect()
 }
 
-/// Extract changed AST nodes by finding nodes that overlap with changed ranges
-/// Returns the actual AST subtrees that were modified or added
-fn extract_changed_nodes<'env>(
-    new_tree: &Tree,
-    old_tree: &Tree,
-    source: &str,
-    env: Env<'env>,
-) -> Vec<HashMap<String, Term<'env>>> {
-    let ranges: Vec<Range> = new_tree.changed_ranges(old_tree).collect();
-    
-    // If we have changed ranges, use them to find changed nodes
-    if !ranges.is_empty() {
-        let mut changed_nodes = Vec::new();
-        let root = new_tree.root_node();
-        
-        // For each changed range, find the smallest AST node that contains it
-        for range in ranges {
-            if let Some(node) = find_smallest_node_containing_range(&root, &range) {
-                // Only include named nodes (skip punctuation/whitespace)
-                if node.is_named() {
-                    let node_map = convert_node_to_map(&node, source, env);
-                    changed_nodes.push(node_map);
+/// Whether a statement carries a heredoc or herestring, whose text must not be re-indented
+fn has_heredoc_redirect(node: &tree_sitter::Node) -> bool {
+    let mut cursor = node.walk();
+    let has_heredoc = node
+        .children(&mut cursor)
+        .any(|child| matches!(child.kind(), "heredoc_redirect" | "herestring_redirect"));
+    has_heredoc
+}
+
+/// Statements the formatter restructures rather than copying verbatim
+fn is_compound(node: &tree_sitter::Node) -> bool {
+    matches!(
+        node.kind(),
+        "if_statement" | "for_statement" | "c_style_for_statement" | "while_statement"
+            | "case_statement" | "compound_statement" | "function_definition"
+    )
+}
+
+/// Append a DOT node statement for node and recurse into its named children
+/// Returns the DOT id assigned to node
+fn write_dot_node(node: &tree_sitter::Node, source: &[u8], dot: &mut String, next_id: &mut usize) -> usize {
+    const SNIPPET_CHARS: usize = 24;
+
+    let id = *next_id;
+    *next_id += 1;
+
+    let text = node_text(node, source);
+    let mut snippet: String = text.chars().take(SNIPPET_CHARS).collect();
+    if text.chars().nth(SNIPPET_CHARS).is_some() {
+        snippet.push_str("...");
+    }
+    let label = format!("{}\n{}", node.kind(), snippet);
+    dot.push_str(&format!("  n{} [label=\"{}\"];\n", id, dot_escape(&label)));
+
+    let mut cursor = node.walk();
+    if cursor.goto_first_child() {
+        loop {
+            let child = cursor.node();
+            if child.is_named() {
+                let field_name = cursor.field_name();
+                let child_id = write_dot_node(&child, source, dot, next_id);
+                match field_name {
+                    Some(field) => dot.push_str(&format!("  n{} -> n{} [label=\"{}\"];\n", id, child_id, field)),
+                    None => dot.push_str(&format!("  n{} -> n{};\n", id, child_id)),
                 }
             }
+            if !cursor.goto_next_sibling() {
+                break;
+            }
         }
-        
-        // Remove duplicates (multiple ranges might map to same node)
-        changed_nodes.dedup_by(|a, b| {
-            // Compare by position to detect duplicates
-            a.get("start_byte") == b.get("start_byte") &&
-            a.get("end_byte") == b.get("end_byte")
-        });
-        
-        return changed_nodes;
     }
-    
-    // If no changed ranges, detect newly added nodes by comparing children counts
-    // This happens when we append new content (e.g., new commands)
-    let old_root = old_tree.root_node();
-    let new_root = new_tree.root_node();
-    let old_child_count = old_root.named_child_count();
-    let new_child_count = new_root.named_child_count();
-    
-    if new_child_count > old_child_count {
-        // Extract the new children that were added
-        let mut new_nodes = Vec::new();
-        let mut cursor = new_root.walk();
-        
-        if cursor.goto_first_child() {
-            let mut index = 0;
-            loop {
-                let child = cursor.node();
-                if child.is_named() {
-                    // Only include nodes beyond the old child count
-                    if index >= old_child_count {
-                        let node_map = convert_node_to_map(&child, source, env);
-                        new_nodes.push(node_map);
-                    }
-                    index += 1;
-                }
-                
-                if !cursor.goto_next_sibling() {
-                    break;
-                }
-            }
+
+    id
+}
+
+/// Escape a label for a double-quoted DOT string (newlines become \n line breaks)
+fn dot_escape(label: &str) -> String {
+    let mut escaped = String::with_capacity(label.len());
+    for c in label.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' | '\t' => escaped.push(' '),
+            _ => escaped.push(c),
         }
-        
-        return new_nodes;
     }
-    
-    // No changes detected
-    vec![]
+    escaped
 }
 
-/// Find the smallest named node that fully contains the given range
-fn find_smallest_node_containing_range<'a>(
-    node: &tree_sitter::Node<'a>,
-    range: &Range,
-) -> Option<tree_sitter::Node<'a>> {
-    // Check if current node contains the range
-    if node.start_byte() > range.start_byte || node.end_byte() < range.end_byte {
+/// Pair a delimiter token with its partner among its parent's children
+/// Openers and closers are matched with a stack, as in unclosed_opener_node; missing partners yield None
+fn delimiter_partner<'tree>(token: &tree_sitter::Node<'tree>) -> Option<tree_sitter::Node<'tree>> {
+    if token.is_named() {
         return None;
     }
-    
-    // Try to find a smaller child that contains the range
-    let mut cursor = node.walk();
-    let mut best_match = if node.is_named() { Some(*node) } else { None };
-    
+    let parent = token.parent()?;
+    let mut stack: Vec<(tree_sitter::Node<'tree>, &'static str)> = Vec::new();
+    let mut cursor = parent.walk();
+
     if cursor.goto_first_child() {
         loop {
             let child = cursor.node();
-            // Recursively search for smaller containing node
-            if let Some(smaller) = find_smallest_node_containing_range(&child, range) {
-                best_match = Some(smaller);
+            if !child.is_named() {
+                let kind = child.kind();
+                match stack.last() {
+                    Some((opener, closer)) if *closer == kind => {
+                        if opener.id() == token.id() {
+                            return (!child.is_missing()).then_some(child);
+                        }
+                        if child.id() == token.id() {
+                            return Some(*opener);
+                        }
+                        stack.pop();
+                    }
+                    _ => {
+                        if let Some(closer) = closing_delimiter(kind) {
+                            stack.push((child, closer));
+                        }
+                    }
+                }
             }
-            
+
             if !cursor.goto_next_sibling() {
                 break;
             }
         }
     }
-    
-    best_match
+
+    None
+}
+
+/// Visit nodes in document order with a tree cursor
+/// The visitor returns whether to descend into the node's children
+fn walk_tree<'tree, F>(root: tree_sitter::Node<'tree>, mut visit: F)
+where
+    F: FnMut(tree_sitter::Node<'tree>) -> bool,
+{
+    let mut cursor = root.walk();
+    loop {
+        if visit(cursor.node()) && cursor.goto_first_child() {
+            continue;
+        }
+
+        loop {
+            if cursor.node() == root {
+                return;
+            }
+            if cursor.goto_next_sibling() {
+                break;
+            }
+            if !cursor.goto_parent() {
+                return;
+            }
+        }
+    }
+}
+
+/// Build a map with a node's byte range and row/column positions
+fn position_map<'env>(node: &tree_sitter::Node, env: Env<'env>) -> HashMap<String, Term<'env>> {
+    use rustler::Encoder;
+
+    let start = node.start_position();
+    let end = node.end_position();
+
+    let mut map = HashMap::new();
+    map.insert("start_byte".to_string(), node.start_byte().encode(env));
+    map.insert("end_byte".to_string(), node.end_byte().encode(env));
+    map.insert("start_row".to_string(), start.row.encode(env));
+    map.insert("start_col".to_string(), start.column.encode(env));
+    map.insert("end_row".to_string(), end.row.encode(env));
+    map.insert("end_col".to_string(), end.column.encode(env));
+    map
+}
+
+/// Encode raw input bytes as a binary, exactly as stored (Elixir strings when they are valid UTF-8)
+fn encode_bytes<'env>(env: Env<'env>, bytes: &[u8]) -> Term<'env> {
+    let mut binary = rustler::NewBinary::new(env, bytes.len());
+    binary.as_mut_slice().copy_from_slice(bytes);
+    rustler::Binary::from(binary).to_term(env)
+}
+
+/// Encode node texts as a list of strings
+fn encode_texts<'env>(env: Env<'env>, texts: &[Cow<str>]) -> Term<'env> {
+    use rustler::Encoder;
+
+    texts.iter().map(|text| &**text).collect::<Vec<&str>>().encode(env)
+}
+
+/// Build an error map with just a "reason" entry
+fn error_map<'env>(env: Env<'env>, reason: &str) -> HashMap<String, Term<'env>> {
+    use rustler::Encoder;
+
+    let mut map = HashMap::new();
+    map.insert("reason".to_string(), reason.encode(env));
+    map
+}
+
+/// NIF result payloads an error map can be returned as: the map itself or an encoded term
+trait ErrorPayload<'env> {
+    fn from_error_map(map: HashMap<String, Term<'env>>, env: Env<'env>) -> Self;
+}
+
+impl<'env> ErrorPayload<'env> for HashMap<String, Term<'env>> {
+    fn from_error_map(map: HashMap<String, Term<'env>>, _: Env<'env>) -> Self {
+        map
+    }
+}
+
+impl<'env> ErrorPayload<'env> for Term<'env> {
+    fn from_error_map(map: HashMap<String, Term<'env>>, env: Env<'env>) -> Self {
+        use rustler::Encoder;
+
+        map.encode(env)
+    }
+}
+
+/// Run f on the stored tree and input, or return the shared {:error, %{"reason" => "no_tree"}}
+/// Locks the tree, then the input, for the duration of f
+fn with_tree<'env, T, F>(env: Env<'env>, resource: &ParserResource, f: F) -> NifResult<(Atom, T)>
+where
+    T: ErrorPayload<'env>,
+    F: FnOnce(&Tree, &InputBuffer) -> NifResult<(Atom, T)>,
+{
+    let tree_lock = resource.old_tree.lock_recover();
+    with_locked_tree(env, tree_lock.as_ref(), &resource.accumulated_input.lock_recover(), f)
+}
+
+/// with_tree for callers already holding the tree and input locks (e.g. through lock_for_edit)
+fn with_locked_tree<'env, T, F>(env: Env<'env>, tree: Option<&Tree>, input: &InputBuffer, f: F) -> NifResult<(Atom, T)>
+where
+    T: ErrorPayload<'env>,
+    F: FnOnce(&Tree, &InputBuffer) -> NifResult<(Atom, T)>,
+{
+    match tree {
+        Some(tree) => f(tree, input),
+        None => Ok((atoms::error(), T::from_error_map(error_map(env, "no_tree"), env))),
+    }
+}
+
+/// Convert a byte offset in source to a row/column point
+/// Column is measured in bytes from the start of the line, as tree-sitter expects
+fn byte_to_point(source: &[u8], byte_offset: usize) -> Point {
+    let prefix = &source[..byte_offset];
+    let row = newline_ends(prefix, 0).count();
+    let line_start = prefix.iter().rposition(|&byte| byte == b'\n').map_or(0, |index| index + 1);
+
+    Point {
+        row,
+        column: byte_offset - line_start,
+    }
+}
+
+/// Find the smallest named node containing a row/column position
+/// Returns None if the position lies outside the source
+fn named_node_at_point<'tree>(tree: &'tree Tree, input: &InputBuffer, row: usize, col: usize) -> Option<tree_sitter::Node<'tree>> {
+    let byte_offset = input.point_to_byte(row, col)?;
+    tree.root_node().named_descendant_for_byte_range(byte_offset, byte_offset)
+}
+
+/// Convert a byte column to UTF-16 code units within its line
+/// byte_offset is the absolute position and byte_column its offset from the line start
+fn utf16_column(source: &[u8], byte_offset: usize, byte_column: usize) -> usize {
+    String::from_utf8_lossy(&source[byte_offset - byte_column..byte_offset]).encode_utf16().count()
 }
 
 rustler::init!(
@@ -519,15 +5837,434 @@ rustler::init!(
         new_parser_with_size,
         parse_incremental,
         reset_parser,
+        reparse_from_scratch,
         get_current_ast,
         has_errors,
         get_buffer_size,
         get_accumulated_input,
+        run_query,
+        node_at_position,
+        parse_edit,
+        replace_node,
+        try_parse_edit,
+        rename_variable,
+        delete_range,
+        get_current_ast_shallow,
+        get_subtree,
+        get_errors,
+        first_error_position,
+        get_error_context,
+        new_parser_windowed,
+        get_commands,
+        get_function_definitions,
+        get_variable_assignments,
+        get_variable_references,
+        get_command_substitutions,
+        get_process_substitutions,
+        get_heredocs,
+        get_redirections,
+        get_pipelines,
+        get_comments,
+        get_shebang,
+        to_sexp,
+        to_sexp_string,
+        get_current_ast_json,
+        set_timeout_micros,
+        new_parser_cancellable,
+        cancel_parse,
+        new_pool,
+        pool_parse,
+        get_current_ast_utf16,
+        byte_to_point_nif,
+        point_to_byte_nif,
+        node_info_at,
+        get_outline,
+        highlight_nif,
+        highlight_changed,
+        export_state,
+        import_state,
+        clone_parser,
+        get_tree_stats,
+        kind_histogram,
+        new_parser_with_opts,
+        get_current_ast_text_limited,
+        find_dangerous_commands,
+        find_unquoted_expansions,
+        classify_words,
+        language_info,
+        validate_query,
+        parse_incremental_streaming,
+        set_document,
+        find_unterminated,
+        parse_ranges,
+        ancestors_at,
+        node_path_at,
+        node_at_path,
+        next_sibling,
+        prev_sibling,
+        get_input_range,
+        get_line_count,
+        get_byte_offset_for_line,
+        is_complete,
+        get_case_statements,
+        get_loops,
+        format,
+        to_dot,
+        new_parser_auto_flush,
+        parse_incremental_bytes,
+        set_max_buffer_size,
+        parse_bash_lenient,
+        get_tokens,
+        matching_delimiter,
+        classify_offset,
+        contains_command,
+        find_privilege_escalation,
+        language_version,
+        parse_with_callback,
+        provide_chunk,
+        diff_documents,
+        get_test_commands,
+        find_nodes_of_kind,
+        get_arithmetic_expansions,
+        node_source_context,
+        get_aliases,
+        parse_edits,
+        get_last_changed_ranges,
+        lint,
+        get_subshells,
     ],
     load = load_resources
 );
 
+#[allow(non_local_definitions)]
 fn load_resources(env: Env, _: Term) -> bool {
     rustler::resource!(ParserResource, env);
+    rustler::resource!(ParserPool, env);
+    rustler::resource!(ChunkReader, env);
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Tree {
+        new_bash_parser().ok().unwrap().parse(source, None).unwrap()
+    }
+
+    /// Named node kinds in document order, skipping comments (which format may move)
+    fn shape(node: tree_sitter::Node, out: &mut String) {
+        out.push('(');
+        out.push_str(node.kind());
+        let mut cursor = node.walk();
+        for child in node.named_children(&mut cursor).filter(|child| !child.is_extra()) {
+            shape(child, out);
+        }
+        out.push(')');
+    }
+
+    fn comments(tree: &Tree, source: &str) -> Vec<String> {
+        let mut found = Vec::new();
+        walk_tree(tree.root_node(), |node| {
+            if node.kind() == "comment" {
+                found.push(node_text(&node, source.as_bytes()).to_string());
+            }
+            true
+        });
+        found
+    }
+
+    /// Format source and check the result parses cleanly to the same statements and comments
+    fn assert_format_round_trips(source: &str) -> String {
+        let tree = parse(source);
+        assert!(!tree.root_node().has_error(), "input has errors: {source:?}");
+        let formatted = format_tree(&tree, source);
+        let reparsed = parse(&formatted);
+        assert!(!reparsed.root_node().has_error(), "formatted {source:?} has errors: {formatted:?}");
+
+        let (mut before, mut after) = (String::new(), String::new());
+        shape(tree.root_node(), &mut before);
+        shape(reparsed.root_node(), &mut after);
+        assert_eq!(before, after, "formatting {source:?} changed the tree: {formatted:?}");
+        assert_eq!(comments(&tree, source), comments(&reparsed, &formatted), "formatting {source:?} lost a comment");
+        assert_eq!(format_tree(&reparsed, &formatted), formatted, "formatting {source:?} is not idempotent");
+        formatted
+    }
+
+    #[test]
+    fn format_keeps_loop_header_comments_out_of_the_header() {
+        assert_eq!(
+            assert_format_round_trips("for i in 1 2 # nums\ndo echo $i; done\n"),
+            "for i in 1 2; do\n  # nums\n  echo $i\ndone\n"
+        );
+        assert_eq!(
+            assert_format_round_trips("while read x\n# c\ndo echo $x; done\n"),
+            "while read x; do\n  # c\n  echo $x\ndone\n"
+        );
+        assert_format_round_trips("for ((i=0; i<3; i++)) # c\ndo :; done\n");
+        assert_format_round_trips("f() # c\n{ echo; }\n");
+    }
+
+    #[test]
+    fn format_copies_loops_with_comments_inside_the_header() {
+        let source = "while true # c\n false; do :; done\n";
+        assert_eq!(assert_format_round_trips(source), source);
+    }
+
+    #[test]
+    fn format_keeps_condition_comments() {
+        assert_eq!(
+            assert_format_round_trips("if true # check\nthen ls; fi\n"),
+            "if true; then\n  # check\n  ls\nfi\n"
+        );
+        assert_format_round_trips("if a; # x\n b # y\nthen ls; elif c # z\nthen :; else d; fi\n");
+    }
+
+    #[test]
+    fn format_indents_compound_statements() {
+        assert_eq!(
+            assert_format_round_trips("if a; then\nfor x in y; do\necho $x\ndone\nfi\n"),
+            "if a; then\n  for x in y; do\n    echo $x\n  done\nfi\n"
+        );
+        assert_format_round_trips("case $1 in\na|b) echo ab;;\n# other\n*) :;;\nesac\n");
+        assert_format_round_trips("f() {\necho hi # greet\n\n\necho bye\n} > log\n");
+        assert_format_round_trips("cat <<EOF\n  kept\nEOF\nsleep 1 &\n");
+    }
+
+    #[test]
+    fn input_buffer_line_index_tracks_edits() {
+        // Deterministic pseudo-random edits, checked against an index rebuilt from scratch
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = |bound: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % bound as u64) as usize
+        };
+        // Includes bytes that are not valid UTF-8, which the buffer stores as they are
+        let pieces: [&[u8]; 9] = [b"", b"x", b"\n", b"ab\ncd", b"\n\n", "é\n".as_bytes(), b"line\n", b"\xe9\n", b"\xc3"];
+
+        let mut input = InputBuffer::from("first\nsecond\n".to_string());
+        for _ in 0..2000 {
+            let len = input.len();
+            let (a, b) = (next(len + 1), next(len + 1));
+            let (start, end) = (a.min(b), a.max(b));
+            if !input.is_char_boundary(start) || !input.is_char_boundary(end) {
+                continue;
+            }
+            match next(4) {
+                0 => input.push_bytes(pieces[next(pieces.len())]),
+                1 if input.len() > 64 => input.truncate(start),
+                _ => input.replace_range(start..end, pieces[next(pieces.len())]),
+            }
+
+            let rebuilt = InputBuffer::from(input.to_vec());
+            assert_eq!(input.line_starts, rebuilt.line_starts, "index drifted for {:?}", String::from_utf8_lossy(&input));
+            for offset in [0, start, end, input.len()] {
+                if offset > input.len() || !input.is_char_boundary(offset) {
+                    continue;
+                }
+                assert_eq!(input.byte_to_point(offset), byte_to_point(&input, offset));
+            }
+        }
+    }
+
+    #[test]
+    fn input_buffer_converts_points_and_lines() {
+        let input = InputBuffer::from("ab\n\ncd".to_string());
+        assert_eq!(input.line_count(), 3);
+        assert_eq!(input.byte_to_point(2), Point { row: 0, column: 2 });
+        assert_eq!(input.byte_to_point(3), Point { row: 1, column: 0 });
+        assert_eq!(input.byte_to_point(6), Point { row: 2, column: 2 });
+        assert_eq!(input.point_to_byte(0, 2), Some(2));
+        assert_eq!(input.point_to_byte(0, 3), None);
+        assert_eq!(input.point_to_byte(2, 2), Some(6));
+        assert_eq!(input.point_to_byte(3, 0), None);
+        assert_eq!(input.line(0), Some(&b"ab"[..]));
+        assert_eq!(input.line(1), Some(&b""[..]));
+        assert_eq!(input.line(2), Some(&b"cd"[..]));
+        assert_eq!(input.line(3), None);
+
+        let mut input = InputBuffer::from("a\nb".to_string());
+        input.truncate(2);
+        assert_eq!((input.line_count(), input.line(1)), (2, Some(&b""[..])));
+        input.clear();
+        assert_eq!((input.line_count(), input.byte_to_point(0)), (1, Point::default()));
+    }
+
+    #[test]
+    fn char_boundaries_only_exclude_offsets_inside_valid_sequences() {
+        let bytes = "aé".as_bytes();
+        assert!(is_char_boundary(bytes, 1));
+        assert!(!is_char_boundary(bytes, 2));
+        assert!(is_char_boundary(bytes, 3));
+        assert!(!is_char_boundary(bytes, 4));
+
+        // Stray continuation bytes and truncated sequences are single-byte units
+        let bytes = b"a\x80\xe9x\xc3";
+        assert!((0..=bytes.len()).all(|offset| is_char_boundary(bytes, offset)));
+    }
+
+    #[test]
+    fn invalid_utf8_parses_with_byte_offsets_intact() {
+        let source = b"echo caf\xe9 ok\n";
+        let mut parser = new_bash_parser().ok().unwrap();
+        let tree = parse_with_limits(ParseLimits { timeout_micros: 0, cancellation_flag: None }, &mut parser, source, None).unwrap();
+        let command = tree.root_node().named_child(0).unwrap();
+        let argument = command.child_by_field_name("argument").unwrap();
+        assert_eq!(argument.byte_range(), 5..9);
+        assert_eq!(node_text(&argument, source), "caf\u{fffd}");
+        assert_eq!(node_text(&command, source), "echo caf\u{fffd} ok");
+    }
+
+    #[test]
+    fn json_nodes_flag_lossily_decoded_text() {
+        let source = b"echo caf\xe9\n";
+        let mut parser = new_bash_parser().ok().unwrap();
+        let tree = parse_with_limits(ParseLimits { timeout_micros: 0, cancellation_flag: None }, &mut parser, source, None).unwrap();
+        let command = tree.root_node().named_child(0).unwrap();
+        let json = convert_node_to_json(&command, source);
+        assert_eq!((json["text_valid"].as_bool(), json["start_byte"].as_u64(), json["end_byte"].as_u64()), (Some(false), Some(0), Some(9)));
+
+        let name = convert_node_to_json(&command.child_by_field_name("name").unwrap(), source);
+        assert_eq!((name["text"].as_str(), name.get("text_valid")), (Some("echo"), None));
+    }
+
+    #[test]
+    fn edit_order_is_independent_of_batch_order() {
+        let edit = |start, end, text: &str| (start, end, text.to_string());
+        assert_eq!(edit_order(&[edit(5, 8, ""), edit(5, 5, "x")]), Ok(vec![1, 0]));
+        assert_eq!(edit_order(&[edit(5, 5, "x"), edit(5, 8, "")]), Ok(vec![0, 1]));
+        assert_eq!(edit_order(&[edit(8, 8, "y"), edit(5, 8, "")]), Ok(vec![1, 0]));
+        assert_eq!(edit_order(&[edit(5, 5, "a"), edit(5, 5, "b")]), Ok(vec![0, 1]));
+        assert_eq!(edit_order(&[edit(6, 9, ""), edit(5, 8, "")]), Err((1, 0)));
+        assert_eq!(edit_order(&[edit(5, 8, ""), edit(5, 8, "z")]), Err((0, 1)));
+    }
+
+    #[test]
+    fn cancel_before_a_parse_starts_cancels_it_once() {
+        let flag = AtomicUsize::new(1);
+        let limits = ParseLimits { timeout_micros: 0, cancellation_flag: Some(&flag) };
+        let mut parser = new_bash_parser().ok().unwrap();
+        assert_eq!(parse_with_limits(limits, &mut parser, b"echo hi\n", None).err(), Some("cancelled"));
+        assert_eq!(flag.load(Ordering::Relaxed), 0);
+        assert!(parse_with_limits(limits, &mut parser, b"echo hi\n", None).is_ok());
+    }
+
+    #[test]
+    fn undo_splices_restores_input_and_index() {
+        let original = "one\ntwo\nthree\n";
+        let mut input = InputBuffer::from(original.to_string());
+
+        // Highest offset first, as splice_many_and_reparse applies them
+        let (edits, removed): (Vec<InputEdit>, Vec<Vec<u8>>) = [(8, 13, "3\n3"), (4, 4, "1.5\n"), (0, 3, "")]
+            .iter()
+            .map(|&(start, end, text)| apply_splice(&mut input, start, end, text))
+            .unzip();
+        assert_eq!(&*input, b"\n1.5\ntwo\n3\n3\n");
+        assert_eq!(edits[0].new_end_position, Point { row: 3, column: 1 });
+        assert_eq!(edits[1].old_end_position, Point { row: 1, column: 0 });
+        assert_eq!(removed, [&b"three"[..], b"", b"one"]);
+
+        undo_splices(&mut input, &edits, removed);
+        assert_eq!(&*input, original.as_bytes());
+        assert_eq!(input.line_starts, InputBuffer::from(original.to_string()).line_starts);
+    }
+
+    #[test]
+    fn minimal_edit_span_trims_common_prefix_and_suffix() {
+        assert_eq!(minimal_edit_span(b"hello world", b"hello there"), (6, 11, 11));
+        assert_eq!(minimal_edit_span(b"ab", b"axb"), (1, 1, 2));
+        assert_eq!(minimal_edit_span(b"axb", b"ab"), (1, 2, 1));
+        assert_eq!(minimal_edit_span(b"same", b"same"), (4, 4, 4));
+        assert_eq!(minimal_edit_span(b"", b"new"), (0, 0, 3));
+        // The prefix and suffix may not overlap when the text repeats
+        assert_eq!(minimal_edit_span(b"aaa", b"aaaa"), (3, 3, 4));
+        // "é" and "è" share their first byte; the span must not split the character
+        assert_eq!(minimal_edit_span("é".as_bytes(), "è".as_bytes()), (0, 2, 2));
+        assert_eq!(minimal_edit_span("xé".as_bytes(), "xè".as_bytes()), (1, 3, 3));
+    }
+
+    #[test]
+    fn merge_ranges_sorts_and_coalesces() {
+        let range = |start_byte: usize, end_byte: usize| Range {
+            start_byte,
+            end_byte,
+            start_point: Point { row: 0, column: start_byte },
+            end_point: Point { row: 0, column: end_byte },
+        };
+        let spans = |ranges: Vec<Range>| -> Vec<(usize, usize, usize)> {
+            ranges.iter().map(|range| (range.start_byte, range.end_byte, range.end_point.column)).collect()
+        };
+
+        assert_eq!(spans(merge_ranges(Vec::new())), []);
+        assert_eq!(
+            spans(merge_ranges(vec![range(10, 12), range(0, 4), range(3, 6), range(6, 8), range(1, 2)])),
+            [(0, 8, 8), (10, 12, 12)]
+        );
+        assert_eq!(spans(merge_ranges(vec![range(0, 10), range(2, 3)])), [(0, 10, 10)]);
+    }
+
+    #[test]
+    fn eviction_point_drops_whole_leading_statements() {
+        let source = "a\nb\nc\n";
+        let tree = parse(source);
+        assert_eq!(eviction_point(&tree, 1), 2);
+        assert_eq!(eviction_point(&tree, 3), 4);
+        // The last statement is never evicted
+        assert_eq!(eviction_point(&tree, usize::MAX), 4);
+
+        let tree = parse("a\nb )\nc\n");
+        assert!(tree.root_node().has_error());
+        assert_eq!(eviction_point(&tree, usize::MAX), 4);
+
+        assert_eq!(eviction_point(&parse(""), 1), 0);
+    }
+
+    #[test]
+    fn glob_match_supports_star_and_question_mark() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("rm", "rm"));
+        assert!(!glob_match("rm", "rmdir"));
+        assert!(glob_match("rm*", "rmdir"));
+        assert!(glob_match("?d", "dd"));
+        assert!(!glob_match("?d", "d"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+        assert!(glob_match("*.sh", "déjà.sh"));
+        assert!(glob_match("**x", "x"));
+    }
+
+    #[test]
+    fn input_is_complete_detects_open_constructs() {
+        let complete = |source: &str| input_is_complete(&parse(source), source.as_bytes());
+
+        assert!(complete(""));
+        assert!(complete("echo hi\n"));
+        assert!(complete("if true; then ls; fi\n"));
+        assert!(complete("cat <<EOF\nbody\nEOF\n"));
+        assert!(complete("echo a\\\\\n"));
+        assert!(!complete("if true; then\n"));
+        assert!(!complete("echo \"open\n"));
+        assert!(!complete("echo a \\\n"));
+        assert!(!complete("cat <<EOF\nbody\n"));
+        assert!(!complete("f() {\n"));
+    }
+
+    #[test]
+    fn delimiter_partner_pairs_tokens() {
+        let source = "echo $(ls) \"${x}\"";
+        let tree = parse(source);
+        let token = |start: usize, end: usize| tree.root_node().descendant_for_byte_range(start, end).unwrap();
+        let partner = |start: usize, end: usize| delimiter_partner(&token(start, end)).map(|node| node.byte_range());
+
+        assert_eq!(&source[5..7], "$(");
+        assert_eq!(partner(5, 7), Some(9..10));
+        assert_eq!(partner(9, 10), Some(5..7));
+        assert_eq!(&source[12..14], "${");
+        assert_eq!(partner(12, 14), Some(15..16));
+        assert_eq!(partner(15, 16), Some(12..14));
+        // Named nodes are not delimiters
+        assert_eq!(partner(0, 4), None);
+    }
+}